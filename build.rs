@@ -16,7 +16,7 @@ fn main() {
     );
 
     // get git commit
-    let command = Command::new("git").args(&["rev-parse", "HEAD"]).output();
+    let command = Command::new("git").args(["rev-parse", "HEAD"]).output();
     let commit = match command {
         Ok(output) => String::from_utf8(output.stdout).unwrap(),
         // if error, e.g. build from source with git repo, just show empty string
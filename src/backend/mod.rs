@@ -1,13 +1,13 @@
+use crate::dns::upstream::Nameserver;
 use log::error;
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::vec::Vec;
+use trust_dns_proto::rr::RecordType;
 
 // The core structure of the in-memory backing store for the DNS server.
-// TODO: I've initially intermingled v4 and v6 addresses for simplicity; the
-// server will get back a mix of responses and filter for v4/v6 from there.
-// This may not be a good decision, not sure yet; we can split later if
-// necessary.
+// v4 and v6 addresses are stored intermingled, lookups filter them by the
+// requested record type.
 pub struct DNSBackend {
     // Map of IP -> Network membership.
     // Every container must have an entry in this map, otherwise we will not
@@ -17,10 +17,116 @@ pub struct DNSBackend {
     pub name_mappings: HashMap<String, HashMap<String, Vec<IpAddr>>>,
     // Map of network name to map of IP address to container name.
     pub reverse_mappings: HashMap<String, HashMap<IpAddr, Vec<String>>>,
-    // Map of IP address to DNS server IPs to service queries not handled
-    // directly.
-    // Not implemented in initial version, we will always use host resolvers.
-    //ctr_dns: HashMap<IpAddr, Vec<IpAddr>>,
+    // Map of network name to map of IP address to the TTL of its records.
+    // Only contains addresses whose container or network configured a TTL.
+    pub ttl_mappings: HashMap<String, HashMap<IpAddr, u32>>,
+    // Map of network name to the DNS servers which handle queries not
+    // answered directly. Networks without entry use the host resolvers.
+    pub network_dns: HashMap<String, Vec<Nameserver>>,
+    // Map of IP address to DNS servers to service queries not handled
+    // directly. Containers without entry use the servers of the network.
+    pub ctr_dns: HashMap<IpAddr, Vec<Nameserver>>,
+    // Map of network name to the DNS servers of specific domains, they win
+    // over the DNS servers of the network for names in these domains.
+    pub network_forwards: HashMap<String, Vec<ForwardRule>>,
+    // Map of IP address to the DNS servers of specific domains, they win
+    // over the rules of the network.
+    pub ctr_forwards: HashMap<IpAddr, Vec<ForwardRule>>,
+    // Map of network name to its subnets, aardvark is authoritative for
+    // their reverse zones.
+    pub network_subnets: HashMap<String, Vec<Subnet>>,
+}
+
+// Queries for names in domain, including the domain itself, are sent to
+// servers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForwardRule {
+    // lowercase domain without trailing dot
+    pub domain: String,
+    pub servers: Vec<Nameserver>,
+}
+
+impl ForwardRule {
+    // Whether name, with or without trailing dot, is in the domain.
+    fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.');
+        match name.len().checked_sub(self.domain.len()) {
+            Some(0) => name.eq_ignore_ascii_case(&self.domain),
+            Some(prefix) => {
+                name.as_bytes()[prefix - 1] == b'.'
+                    && name.is_char_boundary(prefix)
+                    && name[prefix..].eq_ignore_ascii_case(&self.domain)
+            }
+            None => false,
+        }
+    }
+}
+
+// An IP network of a container network, e.g. 10.89.0.0/24.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    // Returns None if prefix_len is 0 or longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Subnet> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len == 0 || prefix_len > max_len {
+            return None;
+        }
+        Some(Subnet { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let shift = 32 - u32::from(self.prefix_len);
+                u32::from(net) >> shift == u32::from(*ip) >> shift
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let shift = 128 - u32::from(self.prefix_len);
+                u128::from(net) >> shift == u128::from(*ip) >> shift
+            }
+            _ => false,
+        }
+    }
+
+    // The in-addr.arpa or ip6.arpa zone of ip within the subnet, None if ip
+    // is not in the subnet. The prefix is rounded up to whole labels so the
+    // zone never covers addresses outside the subnet, e.g. 10.89.0.5 in
+    // 10.89.0.0/20 is in 0.89.10.in-addr.arpa.
+    pub fn reverse_zone(&self, ip: &IpAddr) -> Option<String> {
+        if !self.contains(ip) {
+            return None;
+        }
+        let prefix_len = usize::from(self.prefix_len);
+        let zone = match ip {
+            IpAddr::V4(ip) => {
+                let mut labels: Vec<String> = ip.octets()[..(prefix_len + 7) / 8]
+                    .iter()
+                    .map(|octet| octet.to_string())
+                    .collect();
+                labels.reverse();
+                labels.push("in-addr.arpa".to_string());
+                labels.join(".")
+            }
+            IpAddr::V6(ip) => {
+                let mut labels: Vec<String> = ip
+                    .octets()
+                    .iter()
+                    .flat_map(|octet| vec![octet >> 4, octet & 0xf])
+                    .take((prefix_len + 3) / 4)
+                    .map(|nibble| format!("{:x}", nibble))
+                    .collect();
+                labels.reverse();
+                labels.push("ip6.arpa".to_string());
+                labels.join(".")
+            }
+        };
+        Some(zone)
+    }
 }
 
 pub enum DNSResult {
@@ -30,6 +136,9 @@ pub enum DNSResult {
     // We know the IP address of the requester and what networks they are in.
     // However, there were no results for the requested name to look up.
     NXDomain,
+    // We know the IP address of the requester and what networks they are in.
+    // The requested name exists but has no addresses of the requested type.
+    NoData,
     // We do not know the IP address of the requester.
     NoSuchIP,
     // Other, unspecified error occurred.
@@ -40,25 +149,37 @@ impl DNSBackend {
     // Create a new backend from the given set of network mappings.
     // TODO: If we want to optimize even more strongly, we can probably avoid
     // the clone() calls here.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         containers: &HashMap<IpAddr, Vec<String>>,
         networks: &HashMap<String, HashMap<String, Vec<IpAddr>>>,
         reverse: &HashMap<String, HashMap<IpAddr, Vec<String>>>,
+        ttls: &HashMap<String, HashMap<IpAddr, u32>>,
+        network_dns: &HashMap<String, Vec<Nameserver>>,
+        ctr_dns: &HashMap<IpAddr, Vec<Nameserver>>,
+        network_forwards: &HashMap<String, Vec<ForwardRule>>,
+        ctr_forwards: &HashMap<IpAddr, Vec<ForwardRule>>,
+        network_subnets: &HashMap<String, Vec<Subnet>>,
     ) -> DNSBackend {
         DNSBackend {
             ip_mappings: containers.clone(),
             name_mappings: networks.clone(),
             reverse_mappings: reverse.clone(),
+            ttl_mappings: ttls.clone(),
+            network_dns: network_dns.clone(),
+            ctr_dns: ctr_dns.clone(),
+            network_forwards: network_forwards.clone(),
+            ctr_forwards: ctr_forwards.clone(),
+            network_subnets: network_subnets.clone(),
         }
     }
 
     // Handle a single DNS lookup made by a given IP.
     // The name being looked up *must* have the TLD used by the DNS server
     // stripped.
-    // TODO: right now this returns v4 and v6 addresses intermixed and relies on
-    // the caller to sort through them; we could add a v6 bool as an argument
-    // and do it here instead.
-    pub fn lookup(&self, requester: &IpAddr, entry: &str) -> DNSResult {
+    // Only addresses matching record_type are returned, A for v4, AAAA for v6
+    // and ANY for both.
+    pub fn lookup(&self, requester: &IpAddr, entry: &str, record_type: RecordType) -> DNSResult {
         // Normalize lookup entry to lowercase.
         let mut name = entry.to_lowercase();
         let nets = match self.ip_mappings.get(requester) {
@@ -67,6 +188,7 @@ impl DNSBackend {
         };
 
         let mut results: Vec<IpAddr> = Vec::new();
+        let mut found = false;
 
         for net in nets {
             let net_names = match self.name_mappings.get(net) {
@@ -80,28 +202,79 @@ impl DNSBackend {
             if !name.is_empty() {
                 if let Some(lastchar) = name.chars().last() {
                     if lastchar == '.' {
-                        name = (&name[0..name.len() - 1]).to_string();
+                        name = name[0..name.len() - 1].to_string();
                     }
                 }
             }
             if let Some(addrs) = net_names.get(&name) {
-                results.append(&mut addrs.clone());
+                found = true;
+                results.extend(
+                    addrs
+                        .iter()
+                        .filter(|addr| addr_matches_record_type(addr, record_type)),
+                );
             }
         }
 
-        if results.is_empty() {
+        if !found {
             return DNSResult::NXDomain;
         }
+        if results.is_empty() {
+            return DNSResult::NoData;
+        }
 
         DNSResult::Success(results)
     }
 
+    /// Return the TTL configured for records of addr as seen by requester, if
+    /// addr is in several of the requester's networks the lowest TTL wins.
+    pub fn lookup_ttl(&self, requester: &IpAddr, addr: &IpAddr) -> Option<u32> {
+        let nets = self.ip_mappings.get(requester)?;
+        nets.iter()
+            .filter_map(|net| self.network_ttl(net, addr))
+            .min()
+    }
+
+    /// Return the TTL configured for records of addr in the given network.
+    pub fn network_ttl(&self, network: &str, addr: &IpAddr) -> Option<u32> {
+        self.ttl_mappings.get(network)?.get(addr).copied()
+    }
+
+    /// Return the DNS servers configured for the given network, if any.
+    pub fn network_nameservers(&self, network: &str) -> Option<&Vec<Nameserver>> {
+        self.network_dns.get(network)
+    }
+
+    /// Return the DNS servers configured for the container with the given
+    /// IP address, if any.
+    pub fn ctr_nameservers(&self, requester: &IpAddr) -> Option<&Vec<Nameserver>> {
+        self.ctr_dns.get(requester)
+    }
+
+    /// Return the DNS servers of the rule with the longest domain matching
+    /// name, rules of the container with the given IP address win over the
+    /// rules of the network.
+    pub fn forward_nameservers(
+        &self,
+        network: &str,
+        requester: &IpAddr,
+        name: &str,
+    ) -> Option<&Vec<Nameserver>> {
+        let rule = self
+            .ctr_forwards
+            .get(requester)
+            .and_then(|rules| longest_match(rules, name))
+            .or_else(|| {
+                self.network_forwards
+                    .get(network)
+                    .and_then(|rules| longest_match(rules, name))
+            })?;
+        Some(&rule.servers)
+    }
+
     /// Return a single name resolved via mapping if it exists.
     pub fn reverse_lookup(&self, requester: &IpAddr, lookup_ip: &IpAddr) -> Option<&Vec<String>> {
-        let nets = match self.ip_mappings.get(requester) {
-            Some(n) => n,
-            None => return None,
-        };
+        let nets = self.ip_mappings.get(requester)?;
 
         for ips in nets.iter().filter_map(|v| self.reverse_mappings.get(v)) {
             if let Some(names) = ips.get(lookup_ip) {
@@ -111,4 +284,43 @@ impl DNSBackend {
 
         None
     }
+
+    /// Return the names of the container with the given IP address in the
+    /// given network, regardless of the requester.
+    pub fn network_reverse_lookup(
+        &self,
+        network: &str,
+        lookup_ip: &IpAddr,
+    ) -> Option<&Vec<String>> {
+        self.reverse_mappings.get(network)?.get(lookup_ip)
+    }
+
+    /// Return the reverse zone of ip if it is in a subnet of the given
+    /// network or of one of the requester's networks.
+    pub fn reverse_zone(&self, network: &str, requester: &IpAddr, ip: &IpAddr) -> Option<String> {
+        let nets = self.ip_mappings.get(requester);
+        std::iter::once(network)
+            .chain(nets.into_iter().flatten().map(String::as_str))
+            .filter_map(|net| self.network_subnets.get(net))
+            .flatten()
+            .find_map(|subnet| subnet.reverse_zone(ip))
+    }
+}
+
+// The rule with the longest domain matching name.
+fn longest_match<'a>(rules: &'a [ForwardRule], name: &str) -> Option<&'a ForwardRule> {
+    rules
+        .iter()
+        .filter(|rule| rule.matches(name))
+        .max_by_key(|rule| rule.domain.len())
+}
+
+// Whether addr can be returned as answer for the given record type.
+pub fn addr_matches_record_type(addr: &IpAddr, record_type: RecordType) -> bool {
+    match record_type {
+        RecordType::A => addr.is_ipv4(),
+        RecordType::AAAA => addr.is_ipv6(),
+        RecordType::ANY => true,
+        _ => false,
+    }
 }
@@ -1,16 +1,88 @@
 //! Runs the aardvark dns server with provided config
+use crate::dns::coredns::CoreDnsOptions;
+use crate::dns::local::LocalZones;
+use crate::dns::resolv::RESOLV_CONF_FALLBACK_PATH;
 use crate::server::serve;
 use clap::Parser;
 use log::debug;
 use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
-pub struct Run {}
+pub struct Run {
+    /// TTL in seconds of container records, defaults to 60
+    #[clap(long)]
+    ttl: Option<u32>,
+    /// TTL in seconds of negative answers for names in the search domain, defaults to 10
+    #[clap(long)]
+    negative_ttl: Option<u32>,
+    /// Max number of forwarded answers to cache, 0 disables the cache, defaults to 1000
+    #[clap(long)]
+    cache_size: Option<usize>,
+    /// Lower bound in seconds for the ttl of cached answers, defaults to 0
+    #[clap(long)]
+    cache_min_ttl: Option<u32>,
+    /// Upper bound in seconds for the ttl of cached answers, defaults to 86400
+    #[clap(long)]
+    cache_max_ttl: Option<u32>,
+    /// Seconds expired answers are kept to answer queries no upstream resolver answers, defaults to 0 which disables serving stale answers
+    #[clap(long)]
+    serve_stale: Option<u32>,
+    /// Cache hits after which a forwarded answer is refreshed shortly before it expires, 0 disables prefetching, defaults to 3
+    #[clap(long)]
+    prefetch_hits: Option<u32>,
+    /// Save the cache of forwarded answers to the config directory on shutdown and reload and load it at startup
+    #[clap(long)]
+    cache_snapshot: bool,
+    /// Seconds to wait for the answer of a single upstream resolver, at most an equal share of the query timeout for every resolver and attempt, defaults to the timeout option of /etc/resolv.conf
+    #[clap(long)]
+    upstream_timeout: Option<u64>,
+    /// Seconds to wait for any upstream resolver before answering SERVFAIL, defaults to 5
+    #[clap(long)]
+    query_timeout: Option<u64>,
+    /// resolv.conf to use when /etc/resolv.conf only lists loopback nameservers, without usable nameserver in either queries are forwarded to the fallback nameserver and a warning is logged, defaults to /run/systemd/resolve/resolv.conf
+    #[clap(long)]
+    resolv_conf_fallback: Option<String>,
+    /// Nameserver queries are forwarded to when no resolv.conf lists a usable one, defaults to the public resolver 1.1.1.1
+    #[clap(long)]
+    fallback_nameserver: Option<IpAddr>,
+    /// PEM file with the CA certificates trusted for DNS-over-TLS and DNS-over-HTTPS upstream resolvers, defaults to the CA certificates of the host
+    #[clap(long)]
+    tls_ca_file: Option<String>,
+    /// Validate DNSSEC signatures of forwarded answers, bogus answers get SERVFAIL
+    #[clap(long)]
+    dnssec: bool,
+    /// File with the DS or DNSKEY records of the DNSSEC trust anchors, defaults to the key signing keys of the root zone
+    #[clap(long)]
+    dnssec_trust_anchor: Option<String>,
+    /// Comma-separated zones answered locally instead of forwarded, names of zones with a =loopback suffix resolve to the loopback addresses and all others do not exist, an empty list answers nothing locally, defaults to the special-use and locally-served zones of RFC 6761 and RFC 6303 of which the reverse zones of private addresses are forwarded for networks and containers with dns= servers
+    #[clap(long)]
+    local_zones: Option<String>,
+}
 
 impl Run {
     /// The run command runs the aardvark-dns server with the given configuration.
     pub fn new() -> Self {
-        Self {}
+        Self {
+            ttl: None,
+            negative_ttl: None,
+            cache_size: None,
+            cache_min_ttl: None,
+            cache_max_ttl: None,
+            serve_stale: None,
+            prefetch_hits: None,
+            cache_snapshot: false,
+            upstream_timeout: None,
+            query_timeout: None,
+            resolv_conf_fallback: None,
+            fallback_nameserver: None,
+            tls_ca_file: None,
+            dnssec: false,
+            dnssec_trust_anchor: None,
+            local_zones: None,
+        }
     }
 
     pub fn exec(
@@ -24,7 +96,61 @@ impl Run {
             input_dir
         );
 
-        if let Err(er) = serve::serve(&input_dir, port, &filter_search_domain) {
+        let options = CoreDnsOptions {
+            ttl: self.ttl.unwrap_or(60),
+            negative_ttl: self.negative_ttl.unwrap_or(10),
+            cache_size: self.cache_size.unwrap_or(1000),
+            cache_min_ttl: self.cache_min_ttl.unwrap_or(0),
+            cache_max_ttl: self.cache_max_ttl.unwrap_or(86400),
+            serve_stale: self.serve_stale.unwrap_or(0),
+            prefetch_hits: self.prefetch_hits.unwrap_or(3),
+            cache_snapshot: self.cache_snapshot,
+            upstream_timeout: self.upstream_timeout.map(Duration::from_secs),
+            query_timeout: Duration::from_secs(self.query_timeout.unwrap_or(5)),
+            resolv_conf_fallback: PathBuf::from(
+                self.resolv_conf_fallback
+                    .as_deref()
+                    .unwrap_or(RESOLV_CONF_FALLBACK_PATH),
+            ),
+            fallback_nameserver: self
+                .fallback_nameserver
+                .unwrap_or(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))),
+            tls_ca_file: self.tls_ca_file.as_ref().map(PathBuf::from),
+            dnssec: self.dnssec,
+            dnssec_trust_anchor: self.dnssec_trust_anchor.as_ref().map(PathBuf::from),
+            local_zones: match &self.local_zones {
+                Some(list) => LocalZones::parse(list)?,
+                None => LocalZones::builtin(),
+            },
+        };
+
+        if options.upstream_timeout.map_or(false, |t| t.is_zero())
+            || options.query_timeout.is_zero()
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "upstream and query timeout must not be 0",
+            ));
+        }
+
+        if options.cache_min_ttl > options.cache_max_ttl {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "cache min ttl {} is larger than cache max ttl {}",
+                    options.cache_min_ttl, options.cache_max_ttl
+                ),
+            ));
+        }
+
+        if options.dnssec_trust_anchor.is_some() && !options.dnssec {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "a DNSSEC trust anchor needs --dnssec",
+            ));
+        }
+
+        if let Err(er) = serve::serve(&input_dir, port, &filter_search_domain, options) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 format!("Error starting server {}", er),
@@ -1 +1,2 @@
 pub static AARDVARK_PID_FILE: &str = "aardvark.pid";
+pub static AARDVARK_CACHE_FILE: &str = "aardvark.cache";
@@ -1,8 +1,9 @@
-use crate::backend::DNSBackend;
+use crate::backend::{DNSBackend, ForwardRule, Subnet};
+use crate::dns::upstream::Nameserver;
 use log::warn;
 use std::collections::HashMap;
 use std::fs::{metadata, read_dir, read_to_string};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::vec::Vec;
 pub mod constants;
 
@@ -14,6 +15,26 @@ pub mod constants;
 // formatted as:
 // <container ID, space, IPv4 address, space, IPv6 address, space, comma-separated list of name and aliases>
 // Where space is a single space character.
+// Any line may be followed by optional space separated key=value options:
+// ttl=<seconds>: TTL of the container records, on the first line it applies to
+// every container of the network unless the container line sets its own.
+// dns=<comma-separated list of servers>: DNS servers used instead of the
+// host's resolvers for queries on this network, or on a container line for
+// queries from that container which also wins over the network setting.
+// A server is an IP address optionally followed by a port, e.g. 10.0.0.1,
+// 10.0.0.1:5353 or [fd00::1]:5353, the port defaults to 53. DNS-over-TLS
+// servers are written tls://<address>#<name>, e.g. tls://10.0.0.1#dns.example,
+// their certificate must be valid for name and the port defaults to 853.
+// DNS-over-HTTPS servers are written https://<address>[/dns-query]#<name>,
+// e.g. https://10.0.0.1/dns-query#dns.example, the port defaults to 443.
+// forward=<domain>=<comma-separated list of servers>: DNS servers used for
+// names in domain, e.g. forward=corp.example=10.0.0.1. May be given several
+// times, the rule with the longest matching domain wins. Rules of a container
+// line win over the rules of the network and any rule wins over dns=.
+// subnet=<comma-separated list of subnets>: only on the first line, subnets of
+// the network, e.g. subnet=10.89.0.0/24,fd10:89::/64. Reverse lookups of
+// addresses in them are answered with NXDOMAIN unless a container has the
+// address, instead of being forwarded.
 // Returns a complete DNSBackend struct (all that is necessary for looks) and
 
 // Silent clippy: sometimes clippy marks useful tyes as complex and for this case following type is
@@ -40,6 +61,12 @@ pub fn parse_configs(
     let mut container_ips: HashMap<String, Vec<IpAddr>> = HashMap::new();
     let mut reverse: HashMap<String, HashMap<IpAddr, Vec<String>>> = HashMap::new();
     let mut network_names: HashMap<String, HashMap<String, Vec<IpAddr>>> = HashMap::new();
+    let mut ttls: HashMap<String, HashMap<IpAddr, u32>> = HashMap::new();
+    let mut network_dns: HashMap<String, Vec<Nameserver>> = HashMap::new();
+    let mut ctr_dns: HashMap<IpAddr, Vec<Nameserver>> = HashMap::new();
+    let mut network_forwards: HashMap<String, Vec<ForwardRule>> = HashMap::new();
+    let mut ctr_forwards: HashMap<IpAddr, Vec<ForwardRule>> = HashMap::new();
+    let mut network_subnets: HashMap<String, Vec<Subnet>> = HashMap::new();
     let mut listen_ips_4: HashMap<String, Vec<Ipv4Addr>> = HashMap::new();
     let mut listen_ips_6: HashMap<String, Vec<Ipv6Addr>> = HashMap::new();
 
@@ -56,13 +83,17 @@ pub fn parse_configs(
         // rate.
         match config {
             Ok(cfg) => {
-                // dont process aardvark pid files
+                // dont process aardvark pid and cache snapshot files
                 if let Some(path) = cfg.path().file_name() {
-                    if path == constants::AARDVARK_PID_FILE {
+                    if path == constants::AARDVARK_PID_FILE
+                        || path
+                            .to_str()
+                            .map_or(false, |p| p.starts_with(constants::AARDVARK_CACHE_FILE))
+                    {
                         continue;
                     }
                 }
-                let (bind_ips, ctr_entry) = parse_config(cfg.path().as_path())?;
+                let (network_entry, ctr_entry) = parse_config(cfg.path().as_path())?;
 
                 let network_name: String = match cfg.path().file_name() {
                     // This isn't *completely* safe, but I do not foresee many
@@ -81,24 +112,32 @@ pub fn parse_configs(
                         )),
                 };
 
-                for ip in bind_ips {
+                if !network_entry.dns_servers.is_empty() {
+                    network_dns.insert(network_name.clone(), network_entry.dns_servers);
+                }
+                if !network_entry.forwards.is_empty() {
+                    network_forwards.insert(network_name.clone(), network_entry.forwards);
+                }
+                if !network_entry.subnets.is_empty() {
+                    network_subnets.insert(network_name.clone(), network_entry.subnets);
+                }
+
+                for ip in network_entry.bind_addrs {
                     match ip {
                         IpAddr::V4(a) => listen_ips_4
                             .entry(network_name.clone())
-                            .or_insert_with(Vec::new)
+                            .or_default()
                             .push(a),
                         IpAddr::V6(b) => listen_ips_6
                             .entry(network_name.clone())
-                            .or_insert_with(Vec::new)
+                            .or_default()
                             .push(b),
                     }
                 }
 
                 for entry in ctr_entry {
                     // Container network membership
-                    let ctr_networks = network_membership
-                        .entry(entry.id.clone())
-                        .or_insert_with(Vec::new);
+                    let ctr_networks = network_membership.entry(entry.id.clone()).or_default();
 
                     // Keep the network deduplicated
                     if !ctr_networks.contains(&network_name) {
@@ -110,33 +149,51 @@ pub fn parse_configs(
                     if let Some(v4) = entry.v4 {
                         reverse
                             .entry(network_name.clone())
-                            .or_insert_with(HashMap::new)
+                            .or_default()
                             .entry(std::net::IpAddr::V4(v4))
-                            .or_insert_with(Vec::new)
+                            .or_default()
                             .append(&mut entry.aliases.clone());
                         new_ctr_ips.push(IpAddr::V4(v4));
                     }
                     if let Some(v6) = entry.v6 {
                         reverse
                             .entry(network_name.clone())
-                            .or_insert_with(HashMap::new)
+                            .or_default()
                             .entry(std::net::IpAddr::V6(v6))
-                            .or_insert_with(Vec::new)
+                            .or_default()
                             .append(&mut entry.aliases.clone());
                         new_ctr_ips.push(IpAddr::V6(v6));
                     }
 
-                    let ctr_ips = container_ips
-                        .entry(entry.id.clone())
-                        .or_insert_with(Vec::new);
+                    let ctr_ips = container_ips.entry(entry.id.clone()).or_default();
                     ctr_ips.append(&mut new_ctr_ips.clone());
 
+                    // Record TTLs, container setting wins over network setting
+                    if let Some(ttl) = entry.ttl.or(network_entry.ttl) {
+                        let network_ttls = ttls.entry(network_name.clone()).or_default();
+                        for ip in &new_ctr_ips {
+                            network_ttls.insert(*ip, ttl);
+                        }
+                    }
+
+                    // Container DNS servers
+                    if !entry.dns_servers.is_empty() {
+                        for ip in &new_ctr_ips {
+                            ctr_dns.insert(*ip, entry.dns_servers.clone());
+                        }
+                    }
+
+                    // Container forwarding rules
+                    if !entry.forwards.is_empty() {
+                        for ip in &new_ctr_ips {
+                            ctr_forwards.insert(*ip, entry.forwards.clone());
+                        }
+                    }
+
                     // Network aliases to IPs map.
-                    let network_aliases = network_names
-                        .entry(network_name.clone())
-                        .or_insert_with(HashMap::new);
+                    let network_aliases = network_names.entry(network_name.clone()).or_default();
                     for alias in entry.aliases {
-                        let alias_entries = network_aliases.entry(alias).or_insert_with(Vec::new);
+                        let alias_entries = network_aliases.entry(alias).or_default();
                         alias_entries.append(&mut new_ctr_ips.clone());
                     }
                 }
@@ -152,7 +209,7 @@ pub fn parse_configs(
         match network_membership.get(&ctr_id) {
             Some(s) => {
                 for ip in ips {
-                    let ip_networks = ctrs.entry(ip).or_insert_with(Vec::new);
+                    let ip_networks = ctrs.entry(ip).or_default();
                     ip_networks.append(&mut s.clone());
                 }
             }
@@ -169,26 +226,203 @@ pub fn parse_configs(
     }
 
     Ok((
-        DNSBackend::new(&ctrs, &network_names, &reverse),
+        DNSBackend::new(
+            &ctrs,
+            &network_names,
+            &reverse,
+            &ttls,
+            &network_dns,
+            &ctr_dns,
+            &network_forwards,
+            &ctr_forwards,
+            &network_subnets,
+        ),
         listen_ips_4,
         listen_ips_6,
     ))
 }
 
+// The network wide first line of a config file
+struct NetworkEntry {
+    bind_addrs: Vec<IpAddr>,
+    ttl: Option<u32>,
+    dns_servers: Vec<Nameserver>,
+    forwards: Vec<ForwardRule>,
+    subnets: Vec<Subnet>,
+}
+
 // A single entry in a config file
 struct CtrEntry {
     id: String,
     v4: Option<Ipv4Addr>,
     v6: Option<Ipv6Addr>,
     aliases: Vec<String>,
+    ttl: Option<u32>,
+    dns_servers: Vec<Nameserver>,
+    forwards: Vec<ForwardRule>,
+}
+
+// Split the optional key=value options following the fixed fields of a line.
+fn parse_options<'a>(
+    path: &std::path::Path,
+    line: &str,
+    fields: &[&'a str],
+) -> Result<Vec<(&'a str, &'a str)>, std::io::Error> {
+    let mut options = Vec::new();
+    for field in fields.iter().filter(|f| !f.is_empty()) {
+        match field.split_once('=') {
+            Some(option) => options.push(option),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "configuration file {} line {} is improperly formatted - option {} is not key=value",
+                        path.to_string_lossy(),
+                        line,
+                        field
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_ttl(value: &str) -> Result<u32, std::io::Error> {
+    match value.parse() {
+        Ok(ttl) => Ok(ttl),
+        Err(e) => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("error parsing ttl {}: {}", value, e),
+        )),
+    }
+}
+
+// Parse a comma-separated list of DNS servers, each an IP address with
+// optional port, DNS-over-TLS and DNS-over-HTTPS servers are prefixed with
+// tls:// or https:// and followed by # and the name their certificate is
+// verified against.
+pub(crate) fn parse_dns_servers(value: &str) -> Result<Vec<Nameserver>, std::io::Error> {
+    let mut servers = Vec::new();
+    for server in value.split(',').filter(|s| !s.is_empty()) {
+        let nameserver = if let Some(tls) = server.strip_prefix("tls://") {
+            let (addr, server_name) = split_server_name(server, tls)?;
+            Nameserver::tls(parse_server_addr(addr, 853)?, server_name)
+        } else if let Some(https) = server.strip_prefix("https://") {
+            let (url, server_name) = split_server_name(server, https)?;
+            // queries are always posted to the well-known path of RFC 8484
+            let addr = url.strip_suffix("/dns-query").unwrap_or(url);
+            if addr.contains('/') {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "error parsing dns server {}: only the /dns-query path is supported",
+                        server
+                    ),
+                ));
+            }
+            Nameserver::https(parse_server_addr(addr, 443)?, server_name)
+        } else {
+            Nameserver::dns(parse_server_addr(server, 53)?)
+        };
+        servers.push(nameserver);
+    }
+    Ok(servers)
+}
+
+// Split the #<server name> suffix of an encrypted DNS server off url, server
+// being the whole entry for errors.
+fn split_server_name<'a>(server: &str, url: &'a str) -> Result<(&'a str, &'a str), std::io::Error> {
+    match url.split_once('#') {
+        Some((addr, server_name)) if !server_name.is_empty() => Ok((addr, server_name)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "error parsing dns server {}: encrypted server needs #<server name>",
+                server
+            ),
+        )),
+    }
+}
+
+// Parse an IP address with optional port, default_port if none is given.
+fn parse_server_addr(server: &str, default_port: u16) -> Result<SocketAddr, std::io::Error> {
+    match server.parse::<SocketAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(_) => match server.parse::<IpAddr>() {
+            Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+            Err(e) => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("error parsing dns server {}: {}", server, e),
+            )),
+        },
+    }
+}
+
+// Parse a forwarding rule, a domain and a comma-separated list of DNS servers
+// separated by =. A later rule for the same domain replaces an earlier one.
+fn parse_forward(value: &str, rules: &mut Vec<ForwardRule>) -> Result<(), std::io::Error> {
+    let (domain, servers) = match value.split_once('=') {
+        Some((domain, servers)) => (domain.trim_end_matches('.').to_lowercase(), servers),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "error parsing forward rule {}: expected domain=servers",
+                    value
+                ),
+            ))
+        }
+    };
+    let servers = parse_dns_servers(servers)?;
+    if domain.is_empty() || servers.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "error parsing forward rule {}: domain and servers must not be empty",
+                value
+            ),
+        ));
+    }
+    rules.retain(|rule| rule.domain != domain);
+    rules.push(ForwardRule { domain, servers });
+    Ok(())
+}
+
+// Parse a comma-separated list of subnets, each an IP address followed by /
+// and the prefix length.
+fn parse_subnets(value: &str) -> Result<Vec<Subnet>, std::io::Error> {
+    let mut subnets = Vec::new();
+    for subnet in value.split(',').filter(|s| !s.is_empty()) {
+        let parsed = subnet.split_once('/').and_then(|(addr, prefix_len)| {
+            Subnet::new(addr.parse().ok()?, prefix_len.parse().ok()?)
+        });
+        match parsed {
+            Some(parsed) => subnets.push(parsed),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "error parsing subnet {}: expected address/prefix length",
+                        subnet
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(subnets)
 }
 
 // Read and parse a single given configuration file
-fn parse_config(path: &std::path::Path) -> Result<(Vec<IpAddr>, Vec<CtrEntry>), std::io::Error> {
+fn parse_config(path: &std::path::Path) -> Result<(NetworkEntry, Vec<CtrEntry>), std::io::Error> {
     let content = read_to_string(path)?;
     let mut is_first = true;
 
     let mut bind_addrs: Vec<IpAddr> = Vec::new();
+    let mut network_ttl: Option<u32> = None;
+    let mut dns_servers: Vec<Nameserver> = Vec::new();
+    let mut forwards: Vec<ForwardRule> = Vec::new();
+    let mut subnets: Vec<Subnet> = Vec::new();
     let mut ctrs: Vec<CtrEntry> = Vec::new();
 
     // Split on newline, parse each line
@@ -197,9 +431,25 @@ fn parse_config(path: &std::path::Path) -> Result<(Vec<IpAddr>, Vec<CtrEntry>),
             continue;
         }
         if is_first {
-            // First line is comma-separated V4 and V6
-            if line.contains(',') {
-                for ip in line.split(',') {
+            let fields = line.split(' ').collect::<Vec<&str>>();
+            let bind_ips = fields[0];
+            for (key, value) in parse_options(path, line, &fields[1..])? {
+                match key {
+                    "ttl" => network_ttl = Some(parse_ttl(value)?),
+                    "dns" => dns_servers = parse_dns_servers(value)?,
+                    "forward" => parse_forward(value, &mut forwards)?,
+                    "subnet" => subnets = parse_subnets(value)?,
+                    _ => warn!(
+                        "Ignoring unknown option {} in configuration file {}",
+                        key,
+                        path.to_string_lossy()
+                    ),
+                }
+            }
+
+            // First field is comma-separated V4 and V6
+            if bind_ips.contains(',') {
+                for ip in bind_ips.split(',') {
                     let local_ip = match ip.parse() {
                         Ok(l) => l,
                         Err(e) => {
@@ -212,12 +462,12 @@ fn parse_config(path: &std::path::Path) -> Result<(Vec<IpAddr>, Vec<CtrEntry>),
                     bind_addrs.push(local_ip);
                 }
             } else {
-                let local_ip = match line.parse() {
+                let local_ip = match bind_ips.parse() {
                     Ok(l) => l,
                     Err(e) => {
                         return Err(std::io::Error::new(
                             std::io::ErrorKind::Other,
-                            format!("error parsing ip address {}: {}", line, e),
+                            format!("error parsing ip address {}: {}", bind_ips, e),
                         ))
                     }
                 };
@@ -230,7 +480,7 @@ fn parse_config(path: &std::path::Path) -> Result<(Vec<IpAddr>, Vec<CtrEntry>),
 
         // Split on space
         let parts = line.split(' ').collect::<Vec<&str>>();
-        if parts.len() != 4 {
+        if parts.len() < 4 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!(
@@ -284,11 +534,30 @@ fn parse_config(path: &std::path::Path) -> Result<(Vec<IpAddr>, Vec<CtrEntry>),
             ));
         }
 
+        let mut ttl: Option<u32> = None;
+        let mut ctr_dns_servers: Vec<Nameserver> = Vec::new();
+        let mut ctr_forwards: Vec<ForwardRule> = Vec::new();
+        for (key, value) in parse_options(path, line, &parts[4..])? {
+            match key {
+                "ttl" => ttl = Some(parse_ttl(value)?),
+                "dns" => ctr_dns_servers = parse_dns_servers(value)?,
+                "forward" => parse_forward(value, &mut ctr_forwards)?,
+                _ => warn!(
+                    "Ignoring unknown option {} in configuration file {}",
+                    key,
+                    path.to_string_lossy()
+                ),
+            }
+        }
+
         ctrs.push(CtrEntry {
             id: parts[0].to_string().to_lowercase(),
             v4: v4_addr,
             v6: v6_addr,
             aliases,
+            ttl,
+            dns_servers: ctr_dns_servers,
+            forwards: ctr_forwards,
         });
     }
 
@@ -303,5 +572,14 @@ fn parse_config(path: &std::path::Path) -> Result<(Vec<IpAddr>, Vec<CtrEntry>),
         ));
     }
 
-    Ok((bind_addrs, ctrs))
+    Ok((
+        NetworkEntry {
+            bind_addrs,
+            ttl: network_ttl,
+            dns_servers,
+            forwards,
+            subnets,
+        },
+        ctrs,
+    ))
 }
@@ -0,0 +1,465 @@
+//! Cache of answers received from upstream resolvers.
+//!
+//! The cache is shared by every listener and outlives config reloads, it is
+//! safe to use from multiple threads and tokio runtimes at the same time.
+//! Answers are stored per set of upstream resolvers as networks may use
+//! different resolvers which give different answers for the same name.
+//! Positive answers are kept for the lowest ttl of their records, negative
+//! answers (NXDOMAIN/NODATA) for the negative ttl of the SOA in their
+//! authority section as described in RFC 2308. Negative answers without
+//! SOA, truncated answers and all other response codes are never cached.
+//! Expired answers may be kept for a stale window to answer queries no
+//! upstream resolver answers, see RFC 8767. Hits of every answer are counted
+//! so popular answers can be refreshed shortly before they expire. The
+//! cache can be saved to a file and loaded again by a new process.
+use crate::config::parse_dns_servers;
+use crate::dns::upstream::Nameserver;
+use data_encoding::BASE64;
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trust_dns_client::rr::Name;
+use trust_dns_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{DNSClass, RData, Record, RecordType},
+};
+
+// Upper bound for negative answers regardless of the configured max ttl,
+// see RFC 2308 section 5.
+const MAX_NEGATIVE_TTL: u32 = 10800;
+// Ttl of stale answers, see RFC 8767 section 4.
+const STALE_TTL: u32 = 30;
+// Resolvers of a snapshot line whose key has none.
+const NO_UPSTREAMS: &str = "-";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    name: Name, // lowercased query name
+    record_type: RecordType,
+    dns_class: DNSClass,
+    dnssec_ok: bool,            // answers with DNSSEC records differ from plain ones
+    checking_disabled: bool,    // answers which are not DNSSEC validated
+    upstreams: Vec<Nameserver>, // resolvers the answer was received from
+}
+
+struct CacheEntry {
+    message: Message,  // upstream answer with clamped record ttls
+    inserted: Instant, // time the answer was received
+    ttl: u32,          // seconds the answer may be served from the cache
+    last_used: u64,    // position in the lru order
+    hits: u32,         // times the answer was returned from the cache
+    prefetching: bool, // a refresh of the answer was started
+}
+
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    lru: BTreeMap<u64, CacheKey>, // least recently used entry first
+    counter: u64,
+}
+
+pub struct ResponseCache {
+    capacity: usize, // max number of entries, 0 disables the cache
+    min_ttl: u32,
+    max_ttl: u32,
+    stale_window: u32,  // seconds expired answers are kept
+    prefetch_hits: u32, // hits after which answers are refreshed, 0 never
+    state: Mutex<CacheState>,
+}
+
+impl ResponseCache {
+    // Create a cache holding at most capacity answers, ttls of cached
+    // answers are clamped to min_ttl..=max_ttl. Expired answers are kept
+    // for stale_window seconds, 0 drops them right away. Answers with
+    // prefetch_hits hits are due for a refresh before they expire, 0
+    // disables prefetching.
+    pub fn new(
+        capacity: usize,
+        min_ttl: u32,
+        max_ttl: u32,
+        stale_window: u32,
+        prefetch_hits: u32,
+    ) -> ResponseCache {
+        ResponseCache {
+            capacity,
+            min_ttl,
+            max_ttl: max_ttl.max(min_ttl),
+            stale_window,
+            prefetch_hits,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                counter: 0,
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.state.lock() {
+            Ok(state) => state.entries.len(),
+            Err(_) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Cached answer for the question of req sent to upstreams. The answer
+    // carries the id and question of req and record ttls are lowered by the
+    // time spent in the cache, no record outlives the answer itself. Answers
+    // expired for longer than the stale window are removed.
+    pub fn get(&self, req: &Message, upstreams: &[Nameserver]) -> Option<Message> {
+        self.answer(req, upstreams, false)
+    }
+
+    // Expired answer for the question of req sent to upstreams which is
+    // still within the stale window, its records get a short ttl.
+    pub fn get_stale(&self, req: &Message, upstreams: &[Nameserver]) -> Option<Message> {
+        self.answer(req, upstreams, true)
+    }
+
+    fn answer(&self, req: &Message, upstreams: &[Nameserver], stale: bool) -> Option<Message> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let key = cache_key(req, upstreams)?;
+        let mut state = self.state.lock().ok()?;
+
+        let entry = state.entries.get(&key)?;
+        let elapsed = entry.inserted.elapsed().as_secs();
+        if elapsed >= entry.ttl as u64 + self.stale_window as u64 {
+            let last_used = entry.last_used;
+            state.entries.remove(&key);
+            state.lru.remove(&last_used);
+            return None;
+        }
+        if (elapsed >= entry.ttl as u64) != stale {
+            return None;
+        }
+        let elapsed = elapsed as u32;
+
+        let mut resp = entry.message.clone();
+        let ttl = entry.ttl;
+        let last_used = entry.last_used;
+        state.counter += 1;
+        let counter = state.counter;
+        state.lru.remove(&last_used);
+        state.lru.insert(counter, key.clone());
+        if let Some(entry) = state.entries.get_mut(&key) {
+            entry.last_used = counter;
+            if !stale {
+                entry.hits = entry.hits.saturating_add(1);
+            }
+        }
+        drop(state);
+
+        if stale {
+            update_ttls(&mut resp, |_| STALE_TTL);
+            debug!("[{}] answered from cache with stale answer", req.id());
+        } else {
+            update_ttls(&mut resp, |record_ttl| {
+                record_ttl.min(ttl).saturating_sub(elapsed)
+            });
+            debug!("[{}] answered from cache", req.id());
+        }
+        answer_to(&mut resp, req);
+        Some(resp)
+    }
+
+    // Whether the answer for the question of req sent to upstreams is
+    // popular and about to expire, i.e. it had prefetch_hits hits and is in
+    // the last tenth of its ttl. Every answer is due once until its refresh
+    // is done, see prefetch_done().
+    pub fn prefetch_due(&self, req: &Message, upstreams: &[Nameserver]) -> bool {
+        if self.capacity == 0 || self.prefetch_hits == 0 {
+            return false;
+        }
+        let key = match cache_key(req, upstreams) {
+            Some(key) => key,
+            None => return false,
+        };
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+        let entry = match state.entries.get_mut(&key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let remaining = (entry.ttl as u64).saturating_sub(entry.inserted.elapsed().as_secs());
+        if entry.prefetching
+            || entry.hits < self.prefetch_hits
+            || remaining == 0
+            || remaining > (entry.ttl as u64 / 10).max(1)
+        {
+            return false;
+        }
+        entry.prefetching = true;
+        true
+    }
+
+    // The refresh of the answer for the question of req sent to upstreams
+    // ended. A successful one replaced the answer already, after a failed
+    // one the answer is due again so the next hit retries.
+    pub fn prefetch_done(&self, req: &Message, upstreams: &[Nameserver]) {
+        let key = match cache_key(req, upstreams) {
+            Some(key) => key,
+            None => return,
+        };
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if let Some(entry) = state.entries.get_mut(&key) {
+            entry.prefetching = false;
+        }
+    }
+
+    // Store the answer resp of upstreams for the question of req, answers
+    // which must not be cached are ignored.
+    pub fn insert(&self, req: &Message, upstreams: &[Nameserver], resp: &Message) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = match cache_key(req, upstreams) {
+            Some(key) => key,
+            None => return,
+        };
+        let ttl = match self.cache_ttl(resp) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        let mut message = resp.clone();
+        update_ttls(&mut message, |record_ttl| self.clamp(record_ttl));
+        self.store(key, message, Instant::now(), ttl);
+    }
+
+    // Write the answers which may still be returned to path, one per line
+    // with the time they were received, their ttl, the DO and CD bits and
+    // the resolvers of their key (- for none) followed by the answer in wire
+    // format. The old file is replaced at once. Returns the number of answers written.
+    pub fn save(&self, path: &Path) -> io::Result<usize> {
+        let now = SystemTime::now();
+        let mut lines = String::new();
+        let mut count = 0;
+        {
+            let state = match self.state.lock() {
+                Ok(state) => state,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            // least recently used first so loading keeps the lru order
+            for key in state.lru.values() {
+                let entry = match state.entries.get(key) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let age = entry.inserted.elapsed();
+                if age.as_secs() >= entry.ttl as u64 + self.stale_window as u64 {
+                    continue;
+                }
+                let (bytes, received) = match (
+                    entry.message.to_vec(),
+                    now.checked_sub(age)
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok()),
+                ) {
+                    (Ok(bytes), Some(received)) => (bytes, received),
+                    _ => continue,
+                };
+                let upstreams: Vec<String> = key.upstreams.iter().map(|u| u.to_string()).collect();
+                let upstreams = match upstreams.join(",") {
+                    joined if joined.is_empty() => NO_UPSTREAMS.to_string(),
+                    joined => joined,
+                };
+                let _ = writeln!(
+                    lines,
+                    "{} {} {} {} {} {}",
+                    received.as_secs(),
+                    entry.ttl,
+                    key.dnssec_ok as u8,
+                    key.checking_disabled as u8,
+                    upstreams,
+                    BASE64.encode(&bytes)
+                );
+                count += 1;
+            }
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, lines)?;
+        fs::rename(&tmp, path)?;
+        Ok(count)
+    }
+
+    // Load the answers saved to path by save(), the time since they were
+    // received counts against their ttl. Answers expired for longer than the
+    // stale window and malformed lines are skipped. Returns the number of
+    // answers loaded.
+    pub fn load(&self, path: &Path) -> io::Result<usize> {
+        let content = fs::read_to_string(path)?;
+        if self.capacity == 0 {
+            return Ok(0);
+        }
+        let now = SystemTime::now();
+        let mut count = 0;
+        for line in content.lines() {
+            match self.load_line(line, now) {
+                Some(()) => count += 1,
+                None => debug!("Skipping cached answer {:?}", line),
+            }
+        }
+        Ok(count)
+    }
+
+    fn load_line(&self, line: &str, now: SystemTime) -> Option<()> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 6 {
+            return None;
+        }
+        let received = UNIX_EPOCH + Duration::from_secs(fields[0].parse().ok()?);
+        let ttl: u32 = fields[1].parse().ok()?;
+        // a clock set back makes the answer as new as possible
+        let age = now.duration_since(received).unwrap_or_default();
+        if age.as_secs() >= ttl as u64 + self.stale_window as u64 {
+            return None;
+        }
+        let mut upstreams = match fields[4] {
+            NO_UPSTREAMS => Vec::new(),
+            servers => parse_dns_servers(servers).ok()?,
+        };
+        upstreams.sort_unstable();
+        let message = Message::from_vec(&BASE64.decode(fields[5].as_bytes()).ok()?).ok()?;
+        let query = message.queries().first()?;
+        let key = CacheKey {
+            name: query.name().to_lowercase(),
+            record_type: query.query_type(),
+            dns_class: query.query_class(),
+            dnssec_ok: fields[2] == "1",
+            checking_disabled: fields[3] == "1",
+            upstreams,
+        };
+        self.store(key, message, Instant::now().checked_sub(age)?, ttl);
+        Some(())
+    }
+
+    // Add message as answer for key, received at inserted and valid for ttl
+    // seconds, the least recently used answers make room for it.
+    fn store(&self, key: CacheKey, message: Message, inserted: Instant, ttl: u32) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        state.counter += 1;
+        let counter = state.counter;
+        if let Some(old) = state.entries.remove(&key) {
+            state.lru.remove(&old.last_used);
+        }
+        while state.entries.len() >= self.capacity {
+            let oldest = match state.lru.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(evicted) = state.lru.remove(&oldest) {
+                state.entries.remove(&evicted);
+            }
+        }
+        state.lru.insert(counter, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                message,
+                inserted,
+                ttl,
+                last_used: counter,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+    }
+
+    // How long resp may be cached, None if it must not be cached at all.
+    fn cache_ttl(&self, resp: &Message) -> Option<u32> {
+        if resp.message_type() != MessageType::Response || resp.truncated() {
+            return None;
+        }
+
+        let answer_ttl = resp.answers().iter().map(Record::ttl).min();
+        let negative = match resp.response_code() {
+            ResponseCode::NXDomain => true,
+            ResponseCode::NoError => answer_ttl.is_none(),
+            _ => return None,
+        };
+        if !negative {
+            return answer_ttl.map(|ttl| self.clamp(ttl));
+        }
+
+        // negative answers are only cacheable with a SOA, the lower of its
+        // ttl and its minimum field is the negative ttl
+        let negative_ttl = resp.name_servers().iter().find_map(|record| {
+            match (record.record_type(), record.rdata()) {
+                (RecordType::SOA, RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+                _ => None,
+            }
+        })?;
+        let ttl = match answer_ttl {
+            Some(answer_ttl) => answer_ttl.min(negative_ttl),
+            None => negative_ttl,
+        };
+        Some(self.clamp(ttl).min(MAX_NEGATIVE_TTL))
+    }
+
+    fn clamp(&self, ttl: u32) -> u32 {
+        ttl.max(self.min_ttl).min(self.max_ttl)
+    }
+}
+
+pub(crate) fn cache_key(req: &Message, upstreams: &[Nameserver]) -> Option<CacheKey> {
+    // only plain single question queries are cached
+    if req.queries().len() != 1 {
+        return None;
+    }
+    let query = &req.queries()[0];
+    // order of the resolvers does not matter, e.g. with rotate option
+    let mut upstreams = upstreams.to_vec();
+    upstreams.sort_unstable();
+    Some(CacheKey {
+        name: query.name().to_lowercase(),
+        record_type: query.query_type(),
+        dns_class: query.query_class(),
+        dnssec_ok: req.edns().map(|edns| edns.dnssec_ok()).unwrap_or(false),
+        checking_disabled: req.checking_disabled(),
+        upstreams,
+    })
+}
+
+// Turn resp, received for another request with the same question, into the
+// answer of req: it gets the id of req and its question as sent, i.e. with
+// the case of the name used by req.
+pub(crate) fn answer_to(resp: &mut Message, req: &Message) {
+    resp.set_id(req.id())
+        .set_recursion_desired(req.recursion_desired())
+        .take_queries();
+    resp.add_queries(req.queries().to_vec());
+}
+
+// Replace the ttl of every record in msg with f(ttl).
+fn update_ttls<F: Fn(u32) -> u32>(msg: &mut Message, f: F) {
+    let mut answers = msg.take_answers();
+    let mut name_servers = msg.take_name_servers();
+    let mut additionals = msg.take_additionals();
+    for record in answers
+        .iter_mut()
+        .chain(name_servers.iter_mut())
+        .chain(additionals.iter_mut())
+    {
+        record.set_ttl(f(record.ttl()));
+    }
+    msg.insert_answers(answers);
+    msg.insert_name_servers(name_servers);
+    msg.insert_additionals(additionals);
+}
@@ -1,23 +1,100 @@
+use crate::backend::addr_matches_record_type;
 use crate::backend::DNSBackend;
 use crate::backend::DNSResult;
+use crate::dns::dnssec::{Lookup, Security};
+use crate::dns::inflight::Flight;
+use crate::dns::local::LocalZones;
+use crate::dns::pool::{ClientPool, Transport};
+use crate::dns::upstream::{Nameserver, Protocol, Upstreams};
+use crate::dns::zone::Zone;
+use futures_util::future::BoxFuture;
 use futures_util::StreamExt;
 use log::{debug, error, trace, warn};
-use resolv_conf;
 use std::env;
-use std::fs::File;
-use std::io::Read;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::net::UdpSocket;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
 use trust_dns_client::{client::AsyncClient, proto::xfer::SerialMessage, rr::Name};
 use trust_dns_proto::{
-    op::{Message, MessageType, ResponseCode},
-    rr::{DNSClass, RData, Record, RecordType},
-    udp::{UdpClientStream, UdpStream},
+    error::{ProtoError, ProtoErrorKind},
+    op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{
+        dnssec::rdata::DNSSECRecordType,
+        rdata::opt::{EdnsCode, EdnsOption},
+        DNSClass, RData, Record, RecordType,
+    },
+    udp::UdpStream,
     xfer::{dns_handle::DnsHandle, DnsRequest},
     BufStreamHandle,
 };
 
+// Time a tcp client may stay idle between two queries (or while sending
+// a single one) before the server closes the connection, see RFC 7766.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// Largest udp payload the server advertises in its OPT record and
+// will ever send to a client.
+const MAX_UDP_PAYLOAD: u16 = 4096;
+// Udp payload limit for clients which do not use EDNS, see RFC 1035.
+const DEFAULT_UDP_PAYLOAD: u16 = 512;
+// Extended DNS Error option code and the info codes used by the server,
+// see RFC 8914.
+const EDNS_CODE_EDE: u16 = 15;
+const EDE_STALE_ANSWER: u16 = 3;
+const EDE_DNSSEC_BOGUS: u16 = 6;
+const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
+const EDE_NETWORK_ERROR: u16 = 23;
+// Udp payload size advertised to upstream resolvers when resolv.conf asks
+// for EDNS and the client did not use it.
+const UPSTREAM_UDP_PAYLOAD: u16 = 1232;
+
+// Server wide settings which are not part of the network config files.
+#[derive(Clone, Debug)]
+pub struct CoreDnsOptions {
+    // ttl of container records unless their network or container sets one
+    pub ttl: u32,
+    // ttl of negative answers for names in the search domain zone
+    pub negative_ttl: u32,
+    // max number of forwarded answers kept in the cache, 0 disables it
+    pub cache_size: usize,
+    // lower and upper bound for the ttl of cached answers
+    pub cache_min_ttl: u32,
+    pub cache_max_ttl: u32,
+    // seconds expired answers are kept to answer when no upstream resolver
+    // answers, 0 disables serving stale answers
+    pub serve_stale: u32,
+    // cache hits after which an answer is refreshed before it expires, 0
+    // disables prefetching
+    pub prefetch_hits: u32,
+    // keep the cache in a file of the config directory across restarts
+    pub cache_snapshot: bool,
+    // time to wait for the answer of a single upstream resolver, None uses
+    // the timeout option of the host's resolv.conf
+    pub upstream_timeout: Option<Duration>,
+    // time to wait for an answer of any upstream resolver before the
+    // client gets SERVFAIL
+    pub query_timeout: Duration,
+    // resolv.conf used when the host's one only lists loopback nameservers
+    pub resolv_conf_fallback: PathBuf,
+    // nameserver used when no resolv.conf lists a usable one
+    pub fallback_nameserver: IpAddr,
+    // CA certificates trusted for encrypted upstream resolvers, None trusts
+    // the CA certificates of the host
+    pub tls_ca_file: Option<PathBuf>,
+    // validate DNSSEC signatures of forwarded answers
+    pub dnssec: bool,
+    // DS or DNSKEY records of the trust anchors, None uses the root zone's
+    pub dnssec_trust_anchor: Option<PathBuf>,
+    // special-use and locally-served zones answered instead of forwarded
+    pub local_zones: LocalZones,
+}
+
+#[derive(Clone)]
 pub struct CoreDns {
     name: Name,                          // name or origin
     network_name: String,                // raw network name
@@ -27,7 +104,35 @@ pub struct CoreDns {
     kill_switch: Arc<Mutex<bool>>,       // global kill_switch
     filter_search_domain: String,        // filter_search_domain
     rx: async_broadcast::Receiver<bool>, // kill switch receiver
-    resolv_conf: resolv_conf::Config,    // host's parsed /etc/resolv.conf
+    forward: SocketAddr,                 // upstream if resolv.conf has none
+    no_proxy: bool,                      // never forward requests upstream
+    zone: Option<Zone>,                  // zone built from filter_search_domain
+    local_zones: LocalZones,             // zones answered without forwarding
+    ttl: u32,                            // default ttl of container records
+    negative_ttl: u32,                   // ttl of negative answers
+    serial: u32,                         // SOA serial of the served zones
+    upstreams: Upstreams,                // resolv.conf, cache and health
+    upstream_timeout: Option<Duration>,  // timeout of a single upstream resolver
+    query_timeout: Duration,             // timeout of a forwarded query
+    rotation: Arc<AtomicUsize>,          // next first upstream with rotate option
+}
+
+// How forwarded queries are sent to the upstream resolvers, modelled after
+// the options of the host's resolv.conf.
+#[derive(Clone, Copy, Debug)]
+struct ForwardPolicy {
+    upstream_timeout: Duration, // timeout of a single upstream resolver
+    query_timeout: Duration,    // timeout of a forwarded query
+    attempts: u32,              // times every upstream resolver is tried
+}
+
+// What the server decided to do with a single query.
+enum Resolution {
+    // Answer with the given message right away.
+    Reply(Message),
+    // Query cannot be answered locally, forward it to the given upstream
+    // resolvers.
+    Forward(Message, Vec<Nameserver>, ForwardPolicy),
 }
 
 impl CoreDns {
@@ -44,6 +149,8 @@ impl CoreDns {
         kill_switch: Arc<Mutex<bool>>,
         filter_search_domain: String,
         rx: async_broadcast::Receiver<bool>,
+        options: CoreDnsOptions,
+        upstreams: Upstreams,
     ) -> anyhow::Result<Self> {
         // this does not have to be unique, if we fail getting server name later
         // start with empty name
@@ -61,21 +168,20 @@ impl CoreDns {
         }
 
         debug!(
-            "Will Forward dns requests to udp://{:?}:{}",
+            "Will Forward dns requests to udp://{:?}:{} without usable nameserver in resolv.conf",
             forward_addr, forward_port,
         );
 
-        let mut resolv_conf: resolv_conf::Config = resolv_conf::Config::new();
-        let mut buf = Vec::with_capacity(4096);
-        if let Ok(mut f) = File::open("/etc/resolv.conf") {
-            if f.read_to_end(&mut buf).is_ok() {
-                if let Ok(conf) = resolv_conf::Config::parse(&buf) {
-                    resolv_conf = conf;
-                }
-            }
-        }
-
         let network_name = network_name.to_owned();
+        let no_proxy: bool = env::var("AARDVARK_NO_PROXY").is_ok();
+
+        // Every reload creates new servers, so the time of creation is a
+        // good enough serial for the zone.
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+        let zone = Zone::new(&filter_search_domain, serial, options.negative_ttl);
 
         Ok(CoreDns {
             name,
@@ -86,28 +192,36 @@ impl CoreDns {
             kill_switch,
             filter_search_domain,
             rx,
-            resolv_conf,
+            no_proxy,
+            zone,
+            local_zones: options.local_zones,
+            ttl: options.ttl,
+            negative_ttl: options.negative_ttl,
+            serial,
+            upstreams,
+            forward: SocketAddr::new(forward_addr, forward_port),
+            upstream_timeout: options.upstream_timeout,
+            query_timeout: options.query_timeout,
+            rotation: Arc::new(AtomicUsize::new(0)),
         })
     }
 
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        tokio::try_join!(self.register_port())?;
+    pub async fn run(&self) -> anyhow::Result<()> {
+        tokio::try_join!(self.register_port(), self.register_tcp_port())?;
         Ok(())
     }
 
-    // registers port supports udp for now
-    async fn register_port(&mut self) -> anyhow::Result<()> {
+    // registers udp port
+    async fn register_port(&self) -> anyhow::Result<()> {
         debug!("Starting listen on udp {:?}:{}", self.address, self.port);
 
-        let no_proxy: bool = matches!(env::var("AARDVARK_NO_PROXY"), Ok(_));
-
-        // Do we need to serve on tcp anywhere in future ?
+        let mut rx = self.rx.clone();
         let socket = UdpSocket::bind(format!("{}:{}", self.address, self.port)).await?;
         let (mut receiver, sender) = UdpStream::with_bound(socket);
 
         loop {
             tokio::select! {
-                _ = self.rx.recv() => {
+                _ = rx.recv() => {
                     break;
                 },
                 v = receiver.next() => {
@@ -123,224 +237,1108 @@ impl CoreDns {
                         Ok(msg) => {
                             let src_address = msg.addr();
                             let sender = sender.clone();
-                            let (name, record_type, mut req) = match parse_dns_msg(msg) {
+                            let (name, record_type, req) = match parse_dns_msg(msg) {
                                 Some((name, record_type, req)) => (name, record_type, req),
                                 _ => {
                                     error!("None received while parsing dns message, this is not expected server will ignore this message");
                                     continue;
                                 }
                             };
-                            let mut resolved_ip_list: Vec<IpAddr> = Vec::new();
-
-                            // Create debug and trace info for key parameters.
-                            trace!("server name: {:?}", self.name.to_ascii());
-                            debug!("request source address: {:?}", src_address);
-                            trace!("requested record type: {:?}", record_type);
-                            debug!("checking if backend has entry for: {:?}", name);
-                            trace!(
-                                "server backend.name_mappings: {:?}",
-                                self.backend.name_mappings
-                            );
-                            trace!("server backend.ip_mappings: {:?}", self.backend.ip_mappings);
-                            trace!(
-                                 "server backend kill switch: {:?}",
-                                 self.kill_switch.lock().is_ok()
-                            );
 
+                            let client_edns = req.edns().cloned();
 
-                            // if record type is PTR try resolving early and return if record found
-                            if record_type == RecordType::PTR {
-                                let mut ptr_lookup_ip: String;
-                                // Are we IPv4 or IPv6?
-                                if name.contains(".in-addr.arpa.") {
-                                    // IPv4
-                                    ptr_lookup_ip = name.trim_end_matches(".in-addr.arpa.").split('.').rev().collect::<Vec<&str>>().join(".");
-                                } else if name.contains(".ip6.arpa.") {
-                                    // IPv6
-                                    ptr_lookup_ip = name.trim_end_matches(".ip6.arpa.").split('.').rev().collect::<String>();
-                                    // We removed all periods; now we need to insert a : every 4 characters.
-                                    // split_off() reduces the original string to 4 characters and returns the remainder.
-                                    // So append the 4-character and continue going until we run out of characters.
-                                    let mut split: Vec<String> = Vec::new();
-                                    while ptr_lookup_ip.len() > 4 {
-                                        let tmp = ptr_lookup_ip.split_off(4);
-                                        split.push(ptr_lookup_ip);
-                                        ptr_lookup_ip = tmp;
-                                    }
-                                    // Length should be equal to 4 here, but just use > 0 for safety.
-                                    if !ptr_lookup_ip.is_empty() {
-                                        split.push(ptr_lookup_ip);
-                                    }
-                                    ptr_lookup_ip = split.join(":");
-                                } else {
-                                    // Not a valid address, so force parse() to fail
-                                    // TODO: this is ugly and I don't like it
-                                    ptr_lookup_ip = String::from("not an ip");
+                            match self.handle_query(src_address, &name, record_type, req) {
+                                Some(Resolution::Reply(msg)) => {
+                                    reply(sender, src_address, &msg, client_edns.as_ref());
                                 }
-
-                                trace!("Performing lookup reverse lookup for ip: {:?}", ptr_lookup_ip.to_owned());
-                                // We should probably log malformed queries, but for now if-let should be fine.
-                                if let Ok(lookup_ip) = ptr_lookup_ip.parse() {
-                                    if let Some(reverse_lookup) = self.backend.reverse_lookup(&src_address.ip(), &lookup_ip) {
-                                        let mut req_clone = req.clone();
-                                        for entry in reverse_lookup {
-                                            if let Ok(answer) = Name::from_ascii(format!("{}.", entry)) {
-                                                req_clone.add_answer(
-                                                    Record::new()
-                                                        .set_ttl(86400)
-                                                        .set_rr_type(RecordType::PTR)
-                                                        .set_dns_class(DNSClass::IN)
-                                                        .set_rdata(RData::PTR(answer))
-                                                        .clone(),
-                                                );
-                                            }
-                                        }
-                                        reply(sender.clone(), src_address, &req_clone);
-                                    }
-                                };
+                                Some(Resolution::Forward(req, nameservers, forward_policy)) => {
+                                    let upstreams = self.upstreams.clone();
+                                    tokio::spawn(async move {
+                                        let resp = forward_request(nameservers, &upstreams, req, forward_policy).await;
+                                        reply(sender, src_address, &resp, client_edns.as_ref());
+                                    });
+                                }
+                                None => {}
                             }
+                        }
 
-                            // attempt intra network resolution
-                            match self.backend.lookup(&src_address.ip(), name.as_str()) {
-                                // If we go success from backend lookup
-                                DNSResult::Success(_ip_vec) => {
-                                    debug!("Found backend lookup");
-                                    resolved_ip_list = _ip_vec;
-                                }
-                                // For everything else assume the src_address was not in ip_mappings
-                                _ => {
-                                    debug!(
-                                "No backend lookup found, try resolving in current resolvers entry"
+                        Err(e) => error!("Error parsing dns message {:?}", e),
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    // registers tcp port, every accepted connection is served in its own task
+    async fn register_tcp_port(&self) -> anyhow::Result<()> {
+        debug!("Starting listen on tcp {:?}:{}", self.address, self.port);
+
+        let mut rx = self.rx.clone();
+        let listener = TcpListener::bind(format!("{}:{}", self.address, self.port)).await?;
+
+        loop {
+            tokio::select! {
+                _ = rx.recv() => {
+                    break;
+                },
+                conn = listener.accept() => {
+                    match conn {
+                        Ok((stream, peer)) => {
+                            debug!("Accepted tcp connection from {:?}", peer);
+                            let server = self.clone();
+                            tokio::spawn(async move {
+                                server.serve_tcp_connection(stream, peer).await;
+                            });
+                        }
+                        Err(e) => error!("Error accepting tcp connection {:?}", e),
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reads length-prefixed queries from a single tcp connection until the
+    // client closes it, stays idle for too long or the server is stopped.
+    // Queries which must be forwarded are resolved in the background so
+    // clients can pipeline several queries on the same connection, answers
+    // are written back in the order they become available.
+    async fn serve_tcp_connection(self, stream: TcpStream, peer: SocketAddr) {
+        let (mut read_half, mut write_half) = stream.into_split();
+        let (tx, mut responses) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        let writer = tokio::spawn(async move {
+            while let Some(bytes) = responses.recv().await {
+                if let Err(e) = write_half.write_all(&bytes).await {
+                    debug!("Unable to write tcp response to {:?}: {:?}", peer, e);
+                    break;
+                }
+            }
+        });
+
+        let mut rx = self.rx.clone();
+        loop {
+            let len = tokio::select! {
+                _ = rx.recv() => {
+                    break;
+                },
+                len = timeout(TCP_IDLE_TIMEOUT, read_half.read_u16()) => {
+                    match len {
+                        Ok(Ok(len)) => len,
+                        // connection closed by client
+                        Ok(Err(_)) => break,
+                        Err(_) => {
+                            debug!("Closing idle tcp connection from {:?}", peer);
+                            break;
+                        }
+                    }
+                },
+            };
+
+            let mut buf = vec![0; len as usize];
+            match timeout(TCP_IDLE_TIMEOUT, read_half.read_exact(&mut buf)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    debug!("Unable to read tcp query from {:?}: {:?}", peer, e);
+                    break;
+                }
+                Err(_) => {
+                    debug!("Timed out reading tcp query from {:?}", peer);
+                    break;
+                }
+            }
+
+            let (name, record_type, req) = match parse_dns_msg(SerialMessage::new(buf, peer)) {
+                Some((name, record_type, req)) => (name, record_type, req),
+                _ => {
+                    error!("None received while parsing dns message, this is not expected server will ignore this message");
+                    continue;
+                }
+            };
+
+            let client_edns = req.edns().cloned();
+
+            match self.handle_query(peer, &name, record_type, req) {
+                Some(Resolution::Reply(msg)) => {
+                    tcp_reply(&tx, &msg, client_edns.as_ref());
+                }
+                Some(Resolution::Forward(req, nameservers, forward_policy)) => {
+                    let upstreams = self.upstreams.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        let resp =
+                            forward_request(nameservers, &upstreams, req, forward_policy).await;
+                        tcp_reply(&tx, &resp, client_edns.as_ref());
+                    });
+                }
+                None => {}
+            }
+        }
+
+        // Writer exits once every pending forwarded query has been answered
+        // and all senders are dropped.
+        drop(tx);
+        if let Err(e) = writer.await {
+            error!("Error from tcp writer task: {:?}", e);
+        }
+    }
+
+    // Upstream resolvers for queries for name from requester. Servers of the
+    // forwarding rule for the domain of name win over the servers configured
+    // for the container which win over the servers of the network which win
+    // over the host's resolvers. Without any usable host resolver the forward
+    // address given to the server is used. With the rotate option of
+    // resolv.conf every query starts with the next resolver of the list.
+    fn nameservers(
+        &self,
+        resolv_conf: &resolv_conf::Config,
+        requester: &IpAddr,
+        name: &str,
+    ) -> Vec<Nameserver> {
+        let configured = self
+            .backend
+            .forward_nameservers(&self.network_name, requester, name)
+            .or_else(|| self.backend.ctr_nameservers(requester));
+        let mut servers = match configured {
+            Some(servers) => servers.clone(),
+            None => match self.backend.network_nameservers(&self.network_name) {
+                Some(servers) => servers.clone(),
+                // warned about once the resolv.conf files were loaded
+                None if resolv_conf.nameservers.is_empty() => {
+                    vec![Nameserver::dns(self.forward)]
+                }
+                None => resolv_conf
+                    .nameservers
+                    .iter()
+                    .map(|ns| Nameserver::dns(SocketAddr::new(ns.into(), 53)))
+                    .collect(),
+            },
+        };
+        if resolv_conf.rotate && !servers.is_empty() {
+            let first = self.rotation.fetch_add(1, Ordering::Relaxed) % servers.len();
+            servers.rotate_left(first);
+        }
+        servers
+    }
+
+    // Query sent to the upstream resolvers for req, with the edns0 option of
+    // resolv.conf queries of clients without EDNS get an OPT record.
+    fn upstream_request(&self, resolv_conf: &resolv_conf::Config, mut req: Message) -> Message {
+        let validating = self.upstreams.dnssec.is_some();
+        if (resolv_conf.edns0 || validating) && req.edns().is_none() {
+            let mut edns = Edns::new();
+            edns.set_max_payload(UPSTREAM_UDP_PAYLOAD).set_version(0);
+            req.set_edns(edns);
+        }
+        // validation needs the signatures of the answer
+        if validating {
+            req.edns_mut().set_dnssec_ok(true);
+        }
+        req
+    }
+
+    // Timeouts and attempts of forwarded queries, the timeout option given
+    // on the command line wins over the one of resolv.conf.
+    fn forward_policy(&self, resolv_conf: &resolv_conf::Config) -> ForwardPolicy {
+        ForwardPolicy {
+            upstream_timeout: self
+                .upstream_timeout
+                .unwrap_or_else(|| Duration::from_secs(resolv_conf.timeout.max(1) as u64)),
+            query_timeout: self.query_timeout,
+            attempts: resolv_conf.attempts.max(1),
+        }
+    }
+
+    // Try to answer a query from the backend, shared by udp and tcp listeners.
+    fn handle_query(
+        &self,
+        src_address: SocketAddr,
+        name: &str,
+        record_type: RecordType,
+        mut req: Message,
+    ) -> Option<Resolution> {
+        let mut resolved_ip_list: Vec<IpAddr> = Vec::new();
+
+        // Create debug and trace info for key parameters.
+        trace!("server name: {:?}", self.name.to_ascii());
+        debug!("request source address: {:?}", src_address);
+        trace!("requested record type: {:?}", record_type);
+        debug!("checking if backend has entry for: {:?}", name);
+        trace!(
+            "server backend.name_mappings: {:?}",
+            self.backend.name_mappings
+        );
+        trace!("server backend.ip_mappings: {:?}", self.backend.ip_mappings);
+        trace!(
+            "server backend kill switch: {:?}",
+            self.kill_switch.lock().is_ok()
+        );
+
+        let record_name: Name = match Name::from_str_relaxed(name) {
+            Ok(name) => name,
+            Err(e) => {
+                // log and continue server
+                error!("Error while parsing record name: {:?}", e);
+                return None;
+            }
+        };
+
+        // if record type is PTR try resolving early and return if record found
+        if record_type == RecordType::PTR {
+            let mut ptr_lookup_ip: String;
+            // Are we IPv4 or IPv6?
+            if name.contains(".in-addr.arpa.") {
+                // IPv4
+                ptr_lookup_ip = name
+                    .trim_end_matches(".in-addr.arpa.")
+                    .split('.')
+                    .rev()
+                    .collect::<Vec<&str>>()
+                    .join(".");
+            } else if name.contains(".ip6.arpa.") {
+                // IPv6
+                ptr_lookup_ip = name
+                    .trim_end_matches(".ip6.arpa.")
+                    .split('.')
+                    .rev()
+                    .collect::<String>();
+                // We removed all periods; now we need to insert a : every 4 characters.
+                // split_off() reduces the original string to 4 characters and returns the remainder.
+                // So append the 4-character and continue going until we run out of characters.
+                let mut split: Vec<String> = Vec::new();
+                while ptr_lookup_ip.len() > 4 {
+                    let tmp = ptr_lookup_ip.split_off(4);
+                    split.push(ptr_lookup_ip);
+                    ptr_lookup_ip = tmp;
+                }
+                // Length should be equal to 4 here, but just use > 0 for safety.
+                if !ptr_lookup_ip.is_empty() {
+                    split.push(ptr_lookup_ip);
+                }
+                ptr_lookup_ip = split.join(":");
+            } else {
+                // Not a valid address, so force parse() to fail
+                // TODO: this is ugly and I don't like it
+                ptr_lookup_ip = String::from("not an ip");
+            }
+
+            trace!(
+                "Performing lookup reverse lookup for ip: {:?}",
+                ptr_lookup_ip.to_owned()
+            );
+            // We should probably log malformed queries, but for now if-let should be fine.
+            if let Ok(lookup_ip) = ptr_lookup_ip.parse() {
+                let found = match self.backend.reverse_lookup(&src_address.ip(), &lookup_ip) {
+                    Some(names) => Some((
+                        names,
+                        self.backend.lookup_ttl(&src_address.ip(), &lookup_ip),
+                    )),
+                    // like names, addresses of this server's network resolve
+                    // for requesters outside of the container networks
+                    None => self
+                        .backend
+                        .network_reverse_lookup(&self.network_name, &lookup_ip)
+                        .map(|names| {
+                            (
+                                names,
+                                self.backend.network_ttl(&self.network_name, &lookup_ip),
+                            )
+                        }),
+                };
+                if let Some((reverse_lookup, ttl)) = found {
+                    let mut req_clone = req.clone();
+                    req_clone.set_authoritative(true);
+                    let ttl = ttl.unwrap_or(self.ttl);
+                    for entry in reverse_lookup {
+                        if let Ok(answer) = Name::from_ascii(format!("{}.", entry)) {
+                            req_clone.add_answer(
+                                Record::new()
+                                    .set_name(record_name.clone())
+                                    .set_ttl(ttl)
+                                    .set_rr_type(RecordType::PTR)
+                                    .set_dns_class(DNSClass::IN)
+                                    .set_rdata(RData::PTR(answer))
+                                    .clone(),
                             );
-                                    if let Some(container_mappings) = self.backend.name_mappings.get(&self.network_name) {
-                                        for (key, value) in container_mappings {
-
-                                            // if query contains search domain, strip it out.
-                                            // Why? This is a workaround so aardvark works well
-                                            // with setup which was created for dnsname/dnsmasq
-
-                                            let mut request_name = name.as_str().to_owned();
-                                            let mut filter_domain_ndots_complete = self.filter_search_domain.to_owned();
-                                            filter_domain_ndots_complete.push('.');
-
-                                            if request_name.ends_with(&self.filter_search_domain) {
-                                                request_name = match request_name.strip_suffix(&self.filter_search_domain) {
-                                                    Some(value) => value.to_string(),
-                                                     _ => {
-                                                        error!("Unable to parse string suffix, ignore parsing this request");
-                                                        continue;
-                                                    }
-                                                };
-                                                request_name.push('.');
-                                            }
-                                            if request_name.ends_with(&filter_domain_ndots_complete) {
-                                                request_name = match request_name.strip_suffix(&filter_domain_ndots_complete) {
-                                                    Some(value) => value.to_string(),
-                                                     _ => {
-                                                        error!("Unable to parse string suffix, ignore parsing this request");
-                                                        continue;
-                                                    }
-                                                };
-                                                request_name.push('.');
-                                            }
-
-                                            // convert key to fully qualified domain name
-                                            let mut key_fqdn = key.to_owned();
-                                            key_fqdn.push('.');
-                                            if key_fqdn == request_name {
-                                                resolved_ip_list = value.to_vec();
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            let record_name: Name = match Name::from_str_relaxed(name.as_str()) {
-                                Ok(name) => name,
-                                Err(e) => {
-                                    // log and continue server
-                                    error!("Error while parsing record name: {:?}", e);
+                        }
+                    }
+                    return Some(Resolution::Reply(req_clone));
+                }
+                // unknown addresses of container subnets do not exist,
+                // unless a forwarding rule covers their reverse zone
+                if let Some(zone) = self
+                    .backend
+                    .reverse_zone(&self.network_name, &src_address.ip(), &lookup_ip)
+                    .and_then(|zone| Zone::new(&zone, self.serial, self.negative_ttl))
+                {
+                    if self
+                        .backend
+                        .forward_nameservers(&self.network_name, &src_address.ip(), name)
+                        .is_none()
+                    {
+                        debug!("No container with address {}, sending NXDOMAIN", lookup_ip);
+                        let mut resp = req.clone();
+                        resp.set_response_code(ResponseCode::NXDomain);
+                        resp.set_authoritative(true);
+                        resp.add_name_server(zone.soa_record());
+                        return Some(Resolution::Reply(resp));
+                    }
+                }
+            };
+        }
+
+        // answer queries for the zone apex
+        if let Some(zone) = &self.zone {
+            if let Some(resp) = self.zone_answer(zone, &record_name, record_type, &req) {
+                return Some(Resolution::Reply(resp));
+            }
+        }
+
+        // Set once the requested name is known to the backend, even if it has
+        // no addresses of the requested type.
+        let mut name_found = false;
+        // Set if the name was resolved from this server's network mappings
+        // instead of the requester's networks.
+        let mut from_network_mappings = false;
+
+        // attempt intra network resolution
+        match self.backend.lookup(&src_address.ip(), name, record_type) {
+            // If we go success from backend lookup
+            DNSResult::Success(_ip_vec) => {
+                debug!("Found backend lookup");
+                resolved_ip_list = _ip_vec;
+                name_found = true;
+            }
+            DNSResult::NoData => {
+                debug!("Found backend lookup without {} records", record_type);
+                name_found = true;
+            }
+            // For everything else assume the src_address was not in ip_mappings
+            _ => {
+                debug!("No backend lookup found, try resolving in current resolvers entry");
+                if let Some(container_mappings) = self.backend.name_mappings.get(&self.network_name)
+                {
+                    for (key, value) in container_mappings {
+                        // if query contains search domain, strip it out.
+                        // Why? This is a workaround so aardvark works well
+                        // with setup which was created for dnsname/dnsmasq
+
+                        let mut request_name = name.to_owned();
+                        let mut filter_domain_ndots_complete = self.filter_search_domain.to_owned();
+                        filter_domain_ndots_complete.push('.');
+
+                        if request_name.ends_with(&self.filter_search_domain) {
+                            request_name = match request_name
+                                .strip_suffix(&self.filter_search_domain)
+                            {
+                                Some(value) => value.to_string(),
+                                _ => {
+                                    error!("Unable to parse string suffix, ignore parsing this request");
                                     continue;
                                 }
                             };
-                            if !resolved_ip_list.is_empty()
-                                && (record_type == RecordType::A || record_type == RecordType::AAAA)
+                            request_name.push('.');
+                        }
+                        if request_name.ends_with(&filter_domain_ndots_complete) {
+                            request_name = match request_name
+                                .strip_suffix(&filter_domain_ndots_complete)
                             {
-                                for record_addr in resolved_ip_list {
-                                    match record_addr {
-                                        IpAddr::V4(ipv4) => {
-                                            req.add_answer(
-                                                Record::new()
-                                                    .set_name(record_name.clone())
-                                                    .set_ttl(86400)
-                                                    .set_rr_type(RecordType::A)
-                                                    .set_dns_class(DNSClass::IN)
-                                                    .set_rdata(RData::A(ipv4))
-                                                    .clone(),
-                                            );
-                                        }
-                                        IpAddr::V6(ipv6) => {
-                                            req.add_answer(
-                                                Record::new()
-                                                    .set_name(record_name.clone())
-                                                    .set_ttl(86400)
-                                                    .set_rr_type(RecordType::AAAA)
-                                                    .set_dns_class(DNSClass::IN)
-                                                    .set_rdata(RData::AAAA(ipv6))
-                                                    .clone(),
-                                            );
-                                        }
-                                    }
-                                }
-                                reply(sender, src_address, &req);
-                            } else {
-                                debug!("Not found, forwarding dns request for {:?}", name);
-                                let request_name = name.as_str().to_owned();
-                                let filter_search_domain_ndots = self.filter_search_domain.clone() + ".";
-                                if no_proxy || request_name.ends_with(&self.filter_search_domain) || request_name.ends_with(&filter_search_domain_ndots) || request_name.matches('.').count() == 1  {
-                                    let mut nx_message = req.clone();
-                                    nx_message.set_response_code(ResponseCode::NXDomain);
-                                    reply(sender.clone(), src_address, &nx_message);
-                                } else {
-                                    let nameservers = self.resolv_conf.nameservers.clone();
-                                    tokio::spawn(async move {
-                                        // forward dns request to hosts's /etc/resolv.conf
-                                        for nameserver in nameservers {
-                                            let connection = UdpClientStream::<UdpSocket>::new(SocketAddr::new(
-                                                nameserver.into(),
-                                                53,
-                                            ));
-
-                                            if let Ok((cl, req_sender)) = AsyncClient::connect(connection).await {
-                                                let _ = tokio::spawn(req_sender);
-                                                if let Some(resp) = forward_dns_req(cl, req.clone()).await {
-                                                    if reply(sender.clone(), src_address, &resp).is_some() {
-                                                        // request resolved from following resolver so
-                                                        // break and don't try other resolvers
-                                                        break;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    });
+                                Some(value) => value.to_string(),
+                                _ => {
+                                    error!("Unable to parse string suffix, ignore parsing this request");
+                                    continue;
                                 }
-                            }
+                            };
+                            request_name.push('.');
                         }
 
-                        Err(e) => error!("Error parsing dns message {:?}", e),
+                        // convert key to fully qualified domain name
+                        let mut key_fqdn = key.to_owned();
+                        key_fqdn.push('.');
+                        if key_fqdn == request_name {
+                            name_found = true;
+                            from_network_mappings = true;
+                            resolved_ip_list = value
+                                .iter()
+                                .filter(|addr| addr_matches_record_type(addr, record_type))
+                                .cloned()
+                                .collect();
+                        }
                     }
-                },
+                }
+            }
+        }
+
+        // Answer ANY queries with a single RRset only, see RFC 8482.
+        if record_type == RecordType::ANY {
+            let has_v4 = resolved_ip_list.iter().any(|addr| addr.is_ipv4());
+            resolved_ip_list.retain(|addr| addr.is_ipv4() == has_v4);
+        }
+
+        if !resolved_ip_list.is_empty() {
+            // All records of a RRset must share a ttl, use the lowest one.
+            let ttl = resolved_ip_list
+                .iter()
+                .map(|addr| {
+                    if from_network_mappings {
+                        self.backend.network_ttl(&self.network_name, addr)
+                    } else {
+                        self.backend.lookup_ttl(&src_address.ip(), addr)
+                    }
+                    .unwrap_or(self.ttl)
+                })
+                .min()
+                .unwrap_or(self.ttl);
+            for record_addr in resolved_ip_list {
+                match record_addr {
+                    IpAddr::V4(ipv4) => {
+                        req.add_answer(
+                            Record::new()
+                                .set_name(record_name.clone())
+                                .set_ttl(ttl)
+                                .set_rr_type(RecordType::A)
+                                .set_dns_class(DNSClass::IN)
+                                .set_rdata(RData::A(ipv4))
+                                .clone(),
+                        );
+                    }
+                    IpAddr::V6(ipv6) => {
+                        req.add_answer(
+                            Record::new()
+                                .set_name(record_name.clone())
+                                .set_ttl(ttl)
+                                .set_rr_type(RecordType::AAAA)
+                                .set_dns_class(DNSClass::IN)
+                                .set_rdata(RData::AAAA(ipv6))
+                                .clone(),
+                        );
+                    }
+                }
+            }
+            req.set_authoritative(true);
+            Some(Resolution::Reply(req))
+        } else if name_found {
+            // Name exists but there is nothing of the requested type,
+            // answer with NOERROR and an empty answer section (NODATA).
+            debug!("No {} records for {:?}, sending NODATA", record_type, name);
+            Some(Resolution::Reply(self.negative_response(
+                req,
+                &record_name,
+                ResponseCode::NoError,
+            )))
+        } else {
+            debug!("Not found, forwarding dns request for {:?}", name);
+            let filter_search_domain_ndots = self.filter_search_domain.clone() + ".";
+            // names of a domain with forwarding rule are always forwarded,
+            // e.g. to the server of another network
+            let has_rule = self
+                .backend
+                .forward_nameservers(&self.network_name, &src_address.ip(), name)
+                .is_some();
+            // special-use and locally-served names never exist upstream,
+            // except private reverse zones which dns= servers may serve
+            if !has_rule {
+                let own_upstreams = self.backend.ctr_nameservers(&src_address.ip()).is_some()
+                    || self
+                        .backend
+                        .network_nameservers(&self.network_name)
+                        .is_some();
+                if let Some(resp) = self.local_zones.answer(
+                    &record_name,
+                    record_type,
+                    &req,
+                    self.ttl,
+                    self.negative_ttl,
+                    own_upstreams,
+                ) {
+                    debug!("Answering {:?} from local zone", name);
+                    return Some(Resolution::Reply(resp));
+                }
+            }
+            if self.no_proxy
+                || (!has_rule
+                    && (name.ends_with(&self.filter_search_domain)
+                        || name.ends_with(&filter_search_domain_ndots)
+                        || name.matches('.').count() == 1))
+            {
+                Some(Resolution::Reply(self.negative_response(
+                    req,
+                    &record_name,
+                    ResponseCode::NXDomain,
+                )))
+            } else {
+                let resolv_conf = self.upstreams.resolv_conf.get();
+                let nameservers = self.nameservers(&resolv_conf, &src_address.ip(), name);
+                // answers are cached for the request sent upstream
+                let req = self.upstream_request(&resolv_conf, req);
+                let policy = self.forward_policy(&resolv_conf);
+                match self.upstreams.cache.get(&req, &nameservers) {
+                    Some(resp) => {
+                        if self.upstreams.cache.prefetch_due(&req, &nameservers) {
+                            self.prefetch(req, nameservers, policy);
+                        }
+                        Some(Resolution::Reply(resp))
+                    }
+                    None => Some(Resolution::Forward(req, nameservers, policy)),
+                }
+            }
+        }
+    }
+
+    // Refresh the cached answer to req in the background so it is replaced
+    // before it expires, clients asking meanwhile get the old answer.
+    fn prefetch(&self, req: Message, nameservers: Vec<Nameserver>, policy: ForwardPolicy) {
+        debug!("[{}] prefetching answer about to expire", req.id());
+        let upstreams = self.upstreams.clone();
+        tokio::spawn(async move {
+            forward_request(nameservers.clone(), &upstreams, req.clone(), policy).await;
+            upstreams.cache.prefetch_done(&req, &nameservers);
+        });
+    }
+
+    // Answers for the zone apex, None if name is not the apex.
+    // The apex holds the SOA, the NS and, as the NS points to the apex
+    // itself, the address of this server.
+    fn zone_answer(
+        &self,
+        zone: &Zone,
+        name: &Name,
+        record_type: RecordType,
+        req: &Message,
+    ) -> Option<Message> {
+        if name != zone.origin() {
+            return None;
+        }
+
+        let mut resp = req.clone();
+        resp.set_authoritative(true);
+        let address_record = zone.nameserver_record(self.address, self.ttl);
+        match record_type {
+            // ANY gets a single RRset, see RFC 8482.
+            RecordType::SOA | RecordType::ANY => {
+                resp.add_answer(zone.soa_record());
+            }
+            RecordType::NS => {
+                resp.add_answer(zone.ns_record(self.ttl));
+                resp.add_additional(address_record);
+            }
+            _ if address_record.rr_type() == record_type => {
+                resp.add_answer(address_record);
+            }
+            _ => {
+                resp.add_name_server(zone.soa_record());
             }
         }
+        Some(resp)
+    }
 
-        Ok(()) //TODO: My IDE sees this as unreachable code.  Fix when refactoring
+    // NXDOMAIN or NODATA answer, names inside the zone get its SOA in the
+    // authority section so clients know how long to cache the answer. The
+    // server is not authoritative for names outside the zone.
+    fn negative_response(&self, mut req: Message, name: &Name, code: ResponseCode) -> Message {
+        req.set_response_code(code);
+        if let Some(zone) = &self.zone {
+            if zone.contains(name) {
+                req.set_authoritative(true);
+                req.add_name_server(zone.soa_record());
+            }
+        }
+        req
     }
 }
 
-fn reply(mut sender: BufStreamHandle, socket_addr: SocketAddr, msg: &Message) -> Option<()> {
+// Forward dns request to the given resolvers, healthiest first, the first
+// successful answer wins and is stored in the cache. Every resolver gets the
+// upstream timeout of policy to answer and all of them together the query
+// timeout, if none answers in time the client gets SERVFAIL. If the same
+// question is already forwarded to the same resolvers its answer is used.
+// With DNSSEC validation answers must be validated within the query timeout
+// as well, bogus answers are replaced with SERVFAIL.
+async fn forward_request(
+    nameservers: Vec<Nameserver>,
+    upstreams: &Upstreams,
+    req: Message,
+    policy: ForwardPolicy,
+) -> Message {
+    let leader = match upstreams.inflight.join(&req, &nameservers) {
+        Flight::Lead(leader) => leader,
+        Flight::Follow(follower) => match follower.wait(&req).await {
+            Some(resp) => return resp,
+            // the leading query gave up, forward on our own
+            None => return forward_alone(nameservers, upstreams, req, policy).await,
+        },
+    };
+    let resp = forward_alone(nameservers, upstreams, req, policy).await;
+    leader.complete(&resp);
+    resp
+}
+
+// Forward dns request without coalescing, see forward_request.
+async fn forward_alone(
+    nameservers: Vec<Nameserver>,
+    upstreams: &Upstreams,
+    req: Message,
+    policy: ForwardPolicy,
+) -> Message {
+    let ordered = upstreams.health.order(&nameservers);
+    let query = async {
+        let mut resp = query_nameservers(&ordered, upstreams, &req, policy).await?;
+        // clients which disabled checking validate on their own
+        if let (Some(validator), false) = (&upstreams.dnssec, req.checking_disabled()) {
+            let lookup = UpstreamLookup {
+                nameservers: &ordered,
+                upstreams,
+                policy,
+            };
+            match validator.validate(&lookup, &resp).await {
+                Security::Secure => {
+                    resp.set_authentic_data(true);
+                }
+                Security::Insecure => {
+                    resp.set_authentic_data(false);
+                }
+                Security::Bogus(reason) => {
+                    return Err((
+                        EDE_DNSSEC_BOGUS,
+                        format!("DNSSEC validation failed: {}", reason),
+                    ))
+                }
+            }
+        }
+        Ok(resp)
+    };
+    let (info_code, reason) = match timeout(policy.query_timeout, query).await {
+        Ok(Ok(resp)) => {
+            // every resolver failed with an answer of its own
+            if matches!(
+                resp.response_code(),
+                ResponseCode::ServFail | ResponseCode::Refused
+            ) {
+                let reason = format!("upstream resolvers answered {}", resp.response_code());
+                if let Some(stale) = stale_answer(&req, &nameservers, upstreams, &reason) {
+                    return stale;
+                }
+            }
+            upstreams.cache.insert(&req, &nameservers, &resp);
+            return resp;
+        }
+        Ok(Err(failure)) => failure,
+        Err(_) => (
+            EDE_NO_REACHABLE_AUTHORITY,
+            "no upstream resolver answered in time".to_string(),
+        ),
+    };
+    // bogus answers must not be replaced with older ones, see RFC 8767
+    if info_code != EDE_DNSSEC_BOGUS {
+        if let Some(stale) = stale_answer(&req, &nameservers, upstreams, &reason) {
+            return stale;
+        }
+    }
+    warn!("[{}] unable to forward request: {}", req.id(), reason);
+    servfail(req, info_code, &reason)
+}
+
+// Expired answer of the cache for req once its upstream resolvers failed
+// for reason, see RFC 8767. Clients which use EDNS learn that the answer
+// is stale from an Extended DNS Error.
+fn stale_answer(
+    req: &Message,
+    nameservers: &[Nameserver],
+    upstreams: &Upstreams,
+    reason: &str,
+) -> Option<Message> {
+    let mut resp = upstreams.cache.get_stale(req, nameservers)?;
+    warn!("[{}] {}, answering with stale answer", req.id(), reason);
+    resp.edns_mut()
+        .options_mut()
+        .insert(ede_option(EDE_STALE_ANSWER, reason));
+    Some(resp)
+}
+
+// Lookups of the DNSKEY and DS records needed to validate an answer, sent
+// to the resolvers which gave the answer and cached like forwarded answers.
+struct UpstreamLookup<'a> {
+    nameservers: &'a [Nameserver],
+    upstreams: &'a Upstreams,
+    policy: ForwardPolicy,
+}
+
+impl Lookup for UpstreamLookup<'_> {
+    fn lookup(&self, name: &Name, record_type: RecordType) -> BoxFuture<'_, Option<Message>> {
+        let mut req = Message::new();
+        req.set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            // the validator checks the records itself
+            .set_checking_disabled(true)
+            .add_query(Query::query(name.clone(), record_type));
+        req.edns_mut()
+            .set_max_payload(UPSTREAM_UDP_PAYLOAD)
+            .set_version(0)
+            .set_dnssec_ok(true);
+        Box::pin(async move {
+            if let Some(resp) = self.upstreams.cache.get(&req, self.nameservers) {
+                return Some(resp);
+            }
+            let resp = query_nameservers(self.nameservers, self.upstreams, &req, self.policy)
+                .await
+                .ok()?;
+            self.upstreams.cache.insert(&req, self.nameservers, &resp);
+            Some(resp)
+        })
+    }
+}
+
+// Send req to the given resolvers one after another until one answers, the
+// whole list is tried as many times as the attempts of policy. Every try
+// gets an equal share of the query timeout at most. SERVFAIL and REFUSED
+// answers count as failure and the next resolver is tried, if no resolver
+// gives a better answer the last of them is returned. So do truncated
+// answers whose retry over tcp failed, the first of them wins over SERVFAIL
+// and REFUSED answers. On failure returns the Extended DNS Error info code
+// and reason of the last failed resolver. Every outcome is recorded in the
+// health of upstreams.
+async fn query_nameservers(
+    nameservers: &[Nameserver],
+    upstreams: &Upstreams,
+    req: &Message,
+    policy: ForwardPolicy,
+) -> Result<Message, (u16, String)> {
+    let health = &upstreams.health;
+    // every resolver gets its turn before the query times out, even if the
+    // timeout of a single one alone is as long as the query timeout. The
+    // share depends on the list, so it only bounds the try and is never
+    // passed on to the pooled clients.
+    let tries = (nameservers.len() as u32 * policy.attempts).max(1);
+    let share = policy.upstream_timeout.min(policy.query_timeout / tries);
+    let mut failure = (
+        EDE_NO_REACHABLE_AUTHORITY,
+        "no upstream resolvers configured".to_string(),
+    );
+    let mut failed_answer: Option<Message> = None;
+    let mut truncated_answer: Option<Message> = None;
+    let attempts = (0..policy.attempts).flat_map(|_| nameservers.iter());
+    for nameserver in attempts {
+        let start = Instant::now();
+        let result = match timeout(
+            share,
+            query_nameserver(&upstreams.pool, nameserver, req, policy.upstream_timeout),
+        )
+        .await
+        {
+            Ok(Ok(resp)) if resp.truncated() && nameserver.protocol == Protocol::Dns => {
+                // the retry is part of the same try
+                let left = share.saturating_sub(start.elapsed());
+                let result = retry_over_tcp(
+                    &upstreams.pool,
+                    nameserver,
+                    req,
+                    policy.upstream_timeout,
+                    left,
+                )
+                .await;
+                // the client can still retry over tcp on its own if no other
+                // resolver answers
+                if result.is_err() && truncated_answer.is_none() {
+                    truncated_answer = Some(resp);
+                }
+                Ok(result)
+            }
+            result => result,
+        };
+        if !matches!(result, Ok(Ok(_))) {
+            health.record_failure(nameserver);
+        }
+        match result {
+            Ok(Ok(resp)) => match resp.response_code() {
+                ResponseCode::ServFail | ResponseCode::Refused => {
+                    debug!(
+                        "[{}] upstream resolver {} answered {}, trying next one",
+                        req.id(),
+                        nameserver,
+                        resp.response_code()
+                    );
+                    health.record_failure(nameserver);
+                    failed_answer = Some(resp);
+                }
+                // request resolved from following resolver so
+                // don't try other resolvers
+                _ => {
+                    health.record_success(nameserver, start.elapsed());
+                    return Ok(resp);
+                }
+            },
+            Ok(Err(e)) => {
+                failure = match e.kind() {
+                    ProtoErrorKind::Timeout => (
+                        EDE_NO_REACHABLE_AUTHORITY,
+                        format!("upstream resolver {} timed out", nameserver),
+                    ),
+                    _ => (
+                        EDE_NETWORK_ERROR,
+                        format!("upstream resolver {} failed: {}", nameserver, e),
+                    ),
+                };
+            }
+            Err(_) => {
+                failure = (
+                    EDE_NO_REACHABLE_AUTHORITY,
+                    format!("upstream resolver {} timed out", nameserver),
+                );
+            }
+        }
+    }
+    truncated_answer.or(failed_answer).ok_or(failure)
+}
+
+// Send req to nameserver over udp, or over tls for DNS-over-TLS resolvers.
+async fn query_nameserver(
+    pool: &ClientPool,
+    nameserver: &Nameserver,
+    req: &Message,
+    upstream_timeout: Duration,
+) -> Result<Message, ProtoError> {
+    let transport = match nameserver.protocol {
+        Protocol::Dns => Transport::Udp,
+        Protocol::Tls { .. } => Transport::Tls,
+        Protocol::Https { .. } => Transport::Https,
+    };
+    pooled_query(pool, nameserver, transport, req, upstream_timeout).await
+}
+
+// Send req to nameserver with the pooled client of transport, the client is
+// dropped from the pool if it failed for other reasons than a timeout. A tcp,
+// tls or https query is sent once more over a new connection then, as upstream
+// resolvers close idle connections at any time.
+async fn pooled_query(
+    pool: &ClientPool,
+    nameserver: &Nameserver,
+    transport: Transport,
+    req: &Message,
+    upstream_timeout: Duration,
+) -> Result<Message, ProtoError> {
+    let mut reconnected = false;
+    loop {
+        let cl = pool.client(nameserver, transport, upstream_timeout).await?;
+        let result = forward_dns_req(cl, req.clone()).await;
+        match &result {
+            // a timeout says nothing about the client itself
+            Err(e) if !matches!(e.kind(), ProtoErrorKind::Timeout) => {
+                pool.discard(nameserver, transport);
+                if transport != Transport::Udp && !reconnected {
+                    debug!(
+                        "[{}] connection to upstream resolver {} failed: {}, reconnecting",
+                        req.id(),
+                        nameserver,
+                        e
+                    );
+                    reconnected = true;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        return result;
+    }
+}
+
+// Send req again over tcp to the nameserver which gave a truncated answer
+// over udp, the retry must be done within deadline. The pooled connection
+// is created with upstream_timeout, which does not change between queries.
+async fn retry_over_tcp(
+    pool: &ClientPool,
+    nameserver: &Nameserver,
+    req: &Message,
+    upstream_timeout: Duration,
+    deadline: Duration,
+) -> Result<Message, ProtoError> {
+    debug!(
+        "[{}] answer of upstream resolver {} is truncated, retrying over tcp",
+        req.id(),
+        nameserver
+    );
+    let query = pooled_query(pool, nameserver, Transport::Tcp, req, upstream_timeout);
+    match timeout(deadline, query).await {
+        Ok(Ok(resp)) => Ok(resp),
+        Ok(Err(e)) => {
+            warn!(
+                "[{}] tcp retry to upstream resolver {} failed: {}",
+                req.id(),
+                nameserver,
+                e
+            );
+            Err(e)
+        }
+        Err(_) => {
+            warn!(
+                "[{}] tcp retry to upstream resolver {} timed out",
+                req.id(),
+                nameserver
+            );
+            Err(ProtoErrorKind::Timeout.into())
+        }
+    }
+}
+
+// SERVFAIL answer for req, clients which use EDNS also get the reason as
+// Extended DNS Error, see RFC 8914.
+fn servfail(mut req: Message, info_code: u16, reason: &str) -> Message {
+    let edns = req.edns().map(|_| {
+        let mut edns = Edns::new();
+        edns.options_mut().insert(ede_option(info_code, reason));
+        edns
+    });
+
+    req.set_message_type(MessageType::Response)
+        .set_response_code(ResponseCode::ServFail)
+        .set_recursion_available(true);
+    if let Some(edns) = edns {
+        req.set_edns(edns);
+    }
+    req
+}
+
+// Extended DNS Error option with info_code and reason as extra text.
+fn ede_option(info_code: u16, reason: &str) -> EdnsOption {
+    let mut ede = info_code.to_be_bytes().to_vec();
+    ede.extend_from_slice(reason.as_bytes());
+    EdnsOption::Unknown(EDNS_CODE_EDE, ede)
+}
+
+// Turn msg into the response sent to the client. Clients which used EDNS
+// get the server's own OPT record back, for all other clients any OPT
+// record (e.g. from an upstream answer) is removed, see RFC 6891. DNSSEC
+// records the client did not ask for with the DO bit are removed as well.
+fn prepare_response(msg: &Message, client_edns: Option<&Edns>) -> Message {
+    let stripped;
+    let msg = match client_edns {
+        Some(edns) if edns.dnssec_ok() => msg,
+        _ => {
+            stripped = strip_dnssec(msg);
+            &stripped
+        }
+    };
+    let mut response = match client_edns {
+        Some(client_edns) => {
+            let mut edns = Edns::new();
+            edns.set_max_payload(MAX_UDP_PAYLOAD)
+                .set_version(0)
+                .set_dnssec_ok(client_edns.dnssec_ok())
+                .set_rcode_high(msg.response_code().high());
+            // keep the reason of errors for the client
+            if let Some(ede) = msg
+                .edns()
+                .and_then(|e| e.option(EdnsCode::Unknown(EDNS_CODE_EDE)))
+            {
+                edns.options_mut().insert(ede.clone());
+            }
+            let mut response = msg.clone();
+            response.set_edns(edns);
+            response
+        }
+        None => {
+            // Message has no way to drop its OPT record so copy everything else.
+            let mut response = Message::new();
+            response
+                .set_id(msg.id())
+                .set_op_code(msg.op_code())
+                .set_authoritative(msg.authoritative())
+                .set_truncated(msg.truncated())
+                .set_recursion_desired(msg.recursion_desired())
+                .set_recursion_available(msg.recursion_available())
+                .set_authentic_data(msg.authentic_data())
+                .set_checking_disabled(msg.checking_disabled())
+                .set_response_code(msg.response_code())
+                .add_queries(msg.queries().to_vec())
+                .add_answers(msg.answers().to_vec())
+                .add_name_servers(msg.name_servers().to_vec());
+            response.insert_additionals(msg.additionals().to_vec());
+            response
+        }
+    };
+    response.set_message_type(MessageType::Response);
+    response
+}
+
+// msg without the RRSIG, NSEC and NSEC3 records which were only added for
+// DNSSEC validation, unless they were asked for, see RFC 4035 section 3.2.1.
+fn strip_dnssec(msg: &Message) -> Message {
+    let is_dnssec = |record_type: RecordType| {
+        matches!(
+            record_type,
+            RecordType::DNSSEC(
+                DNSSECRecordType::RRSIG | DNSSECRecordType::NSEC | DNSSECRecordType::NSEC3
+            )
+        )
+    };
+    let mut msg = msg.clone();
+    let queried = msg.queries().iter().any(|q| is_dnssec(q.query_type()));
+    let keep = |record: &Record| queried || !is_dnssec(record.rr_type());
+    let answers = msg.take_answers().into_iter().filter(keep).collect();
+    let name_servers = msg.take_name_servers().into_iter().filter(keep).collect();
+    let additionals = msg.take_additionals().into_iter().filter(keep).collect();
+    msg.insert_answers(answers);
+    msg.insert_name_servers(name_servers);
+    msg.insert_additionals(additionals);
+    msg
+}
+
+// Largest udp response the client is able to receive.
+fn udp_payload_size(client_edns: Option<&Edns>) -> usize {
+    match client_edns {
+        Some(edns) => edns
+            .max_payload()
+            .clamp(DEFAULT_UDP_PAYLOAD, MAX_UDP_PAYLOAD) as usize,
+        None => DEFAULT_UDP_PAYLOAD as usize,
+    }
+}
+
+// Serialize msg so it fits into max_size bytes. Additional records are
+// dropped first, if that is not enough authority and answer records are
+// removed from the end as well and the TC bit is set so the client can
+// retry over tcp.
+fn serialize_truncated(msg: &Message, max_size: usize) -> Option<Vec<u8>> {
+    let bytes = msg.to_vec().ok()?;
+    if bytes.len() <= max_size {
+        return Some(bytes);
+    }
+
+    let mut truncated = msg.clone();
+    truncated.take_additionals();
+    let bytes = truncated.to_vec().ok()?;
+    if bytes.len() <= max_size {
+        return Some(bytes);
+    }
+
+    debug!(
+        "[{}] response of {} bytes exceeds {} bytes, truncating",
+        msg.id(),
+        bytes.len(),
+        max_size
+    );
+    truncated.set_truncated(true);
+    truncated.take_name_servers();
+    loop {
+        let bytes = truncated.to_vec().ok()?;
+        if bytes.len() <= max_size || truncated.answers_mut().pop().is_none() {
+            return Some(bytes);
+        }
+    }
+}
+
+fn reply(
+    mut sender: BufStreamHandle,
+    socket_addr: SocketAddr,
+    msg: &Message,
+    client_edns: Option<&Edns>,
+) -> Option<()> {
     let id = msg.id();
-    let mut msg_mut = msg.clone();
-    msg_mut.set_message_type(MessageType::Response);
-    let response = SerialMessage::new(msg_mut.to_vec().ok()?, socket_addr);
+    let msg = prepare_response(msg, client_edns);
+    let bytes = serialize_truncated(&msg, udp_payload_size(client_edns))?;
+    let response = SerialMessage::new(bytes, socket_addr);
 
     match sender.send(response) {
         Ok(_) => {
@@ -354,6 +1352,36 @@ fn reply(mut sender: BufStreamHandle, socket_addr: SocketAddr, msg: &Message) ->
     Some(())
 }
 
+// Same as reply() but for tcp, where every message is prefixed with
+// its length as two byte big-endian integer and no truncation is needed.
+fn tcp_reply(
+    sender: &mpsc::UnboundedSender<Vec<u8>>,
+    msg: &Message,
+    client_edns: Option<&Edns>,
+) -> Option<()> {
+    let id = msg.id();
+    let bytes = prepare_response(msg, client_edns).to_vec().ok()?;
+    if bytes.len() > u16::MAX as usize {
+        error!("[{}] response too large for tcp: {}", id, bytes.len());
+        return None;
+    }
+
+    let mut framed = Vec::with_capacity(bytes.len() + 2);
+    framed.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    framed.extend_from_slice(&bytes);
+
+    match sender.send(framed) {
+        Ok(_) => {
+            debug!("[{}] success tcp reponse", id);
+        }
+        Err(e) => {
+            error!("[{}] fail tcp response: {:?}", id, e);
+        }
+    }
+
+    Some(())
+}
+
 fn parse_dns_msg(body: SerialMessage) -> Option<(String, RecordType, Message)> {
     match Message::from_vec(body.bytes()) {
         Ok(msg) => {
@@ -386,7 +1414,7 @@ fn parse_dns_msg(body: SerialMessage) -> Option<(String, RecordType, Message)> {
     }
 }
 
-async fn forward_dns_req(mut cl: AsyncClient, message: Message) -> Option<Message> {
+async fn forward_dns_req(mut cl: AsyncClient, message: Message) -> Result<Message, ProtoError> {
     let req = DnsRequest::new(message, Default::default());
     let id = req.id();
 
@@ -403,11 +1431,11 @@ async fn forward_dns_req(mut cl: AsyncClient, message: Message) -> Option<Messag
                     answer.rdata(),
                 );
             }
-            Some(response.into())
+            Ok(response.into())
         }
         Err(e) => {
             error!("{} dns request failed: {}", id, e);
-            None
+            Err(e)
         }
     }
 }
@@ -0,0 +1,1032 @@
+//! DNSSEC validation of forwarded answers.
+//!
+//! Validation is optional. Once enabled queries sent upstream ask for DNSSEC
+//! records and every answer is checked before it is cached or returned: the
+//! RRSIGs of its RRsets must verify with the DNSKEYs of the signing zone,
+//! which are authenticated by the DS records of the parent zone and so on up
+//! to a trust anchor. Negative answers need NSEC or NSEC3 records proving the
+//! denial and unsigned data is only accepted below a delegation which is
+//! proven to have no DS records. DNSKEY and DS records are looked up with the
+//! resolvers which gave the answer, as a stub resolver does. Authenticated
+//! DNSKEYs are kept per zone until the first of their records or RRSIGs, or
+//! those of the DS records vouching for them, expires, so the chain of trust
+//! is only walked again for zones whose keys are not known yet.
+//!
+//! Answers are classified as in RFC 4035 section 4.3: secure answers get the
+//! AD bit, insecure ones are passed on as they are and bogus ones must be
+//! answered with SERVFAIL. Names outside of every trust anchor are insecure.
+//!
+//! The validator of trust-dns-proto 0.20, `DnssecDnsHandle`, is not used as
+//! it cannot do what a forwarder needs:
+//!
+//! - negative answers are only checked against NSEC records, NXDOMAIN and
+//!   NODATA answers of NSEC3 zones (most signed TLDs) always fail;
+//! - there is no insecure outcome, unsigned RRsets fail or are silently
+//!   dropped from the answer and delegations without DS records are never
+//!   proven, so unsigned zones below the root cannot be resolved at all;
+//! - a response is rejected only if none of its RRsets verify, otherwise
+//!   the unverified RRsets are removed, so a missing RRSIG cannot be told
+//!   apart from an empty answer and no Extended DNS Error can be given;
+//! - its `TrustAnchor` only holds DNSKEYs, the DS records of the root
+//!   key signing keys as published by IANA cannot be used;
+//! - it wraps a single `DnsHandle`, while DNSKEY and DS lookups here must go
+//!   through the same resolver list, deadlines and cache as the query.
+use futures_util::future::BoxFuture;
+use log::debug;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::dnssec::rdata::{
+    DNSSECRData, DNSSECRecordType, DNSKEY, DS, NSEC, NSEC3, SIG,
+};
+use trust_dns_proto::rr::dnssec::{Algorithm, DigestType, Nsec3HashAlgorithm, Verifier};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+const RRSIG: RecordType = RecordType::DNSSEC(DNSSECRecordType::RRSIG);
+const TYPE_DNSKEY: RecordType = RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+const TYPE_DS: RecordType = RecordType::DNSSEC(DNSSECRecordType::DS);
+const TYPE_NSEC: RecordType = RecordType::DNSSEC(DNSSECRecordType::NSEC);
+const TYPE_NSEC3: RecordType = RecordType::DNSSEC(DNSSECRecordType::NSEC3);
+// not known to trust-dns 0.20
+const DNAME: RecordType = RecordType::Unknown(39);
+
+// Longest chain of zones followed from an answer to its trust anchor.
+const MAX_CHAIN_DEPTH: usize = 32;
+// Most zones whose authenticated DNSKEYs are kept.
+const MAX_CACHED_ZONES: usize = 1000;
+// NSEC3 records with more iterations are not worth the hashing, their
+// denials count as insecure, see RFC 9276.
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+// Key signing keys of the root zone, see https://data.iana.org/root-anchors/.
+const ROOT_TRUST_ANCHORS: &str = "\
+. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+
+// Security of an answer, see RFC 4035 section 4.3.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Security {
+    Secure,
+    Insecure,
+    Bogus(String), // why validation failed
+}
+
+impl Security {
+    // Security of an answer made of parts with the security of self and
+    // other, i.e. the worse of both.
+    fn and(self, other: Security) -> Security {
+        match (self, other) {
+            (Security::Bogus(reason), _) | (_, Security::Bogus(reason)) => Security::Bogus(reason),
+            (Security::Insecure, _) | (_, Security::Insecure) => Security::Insecure,
+            _ => Security::Secure,
+        }
+    }
+}
+
+// Source of the DNSKEY and DS records needed to validate answers.
+pub trait Lookup {
+    // Answer with DNSSEC records to the query for name and record_type,
+    // None if no answer was received.
+    fn lookup(&self, name: &Name, record_type: RecordType) -> BoxFuture<'_, Option<Message>>;
+}
+
+#[derive(Clone, Debug)]
+enum AnchorKey {
+    Ds(DS),
+    Dnskey(DNSKEY),
+}
+
+// Keys trusted without further proof, each for the zone it belongs to.
+#[derive(Clone, Debug)]
+pub struct TrustAnchors {
+    anchors: Vec<(Name, AnchorKey)>,
+}
+
+impl TrustAnchors {
+    // Key signing keys of the root zone.
+    pub fn root() -> TrustAnchors {
+        TrustAnchors::parse(ROOT_TRUST_ANCHORS).expect("invalid built-in trust anchors")
+    }
+
+    // Read trust anchors from path, see parse().
+    pub fn from_file(path: &Path) -> io::Result<TrustAnchors> {
+        TrustAnchors::parse(&read_to_string(path)?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("error parsing trust anchors {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    // Parse DS and DNSKEY records in zone file format, one record per line
+    // with owner, optional ttl and class, type and data, e.g.
+    // `. IN DS 20326 8 2 E06D44B8...`. Everything after a ; is a comment.
+    pub fn parse(text: &str) -> io::Result<TrustAnchors> {
+        let mut anchors = Vec::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            anchors.push(parse_anchor(line).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", line, e))
+            })?);
+        }
+        if anchors.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no trust anchor found",
+            ));
+        }
+        Ok(TrustAnchors { anchors })
+    }
+
+    pub fn len(&self) -> usize {
+        self.anchors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.anchors.is_empty()
+    }
+
+    // Zone of the closest trust anchor at or above name.
+    fn closest(&self, name: &Name) -> Option<&Name> {
+        self.anchors
+            .iter()
+            .map(|(zone, _)| zone)
+            .filter(|zone| zone.zone_of(name))
+            .max_by_key(|zone| zone.num_labels())
+    }
+
+    fn keys(&self, zone: &Name) -> impl Iterator<Item = &AnchorKey> {
+        let zone = zone.clone();
+        self.anchors
+            .iter()
+            .filter(move |(name, _)| *name == zone)
+            .map(|(_, key)| key)
+    }
+}
+
+fn parse_anchor(line: &str) -> Result<(Name, AnchorKey), String> {
+    let mut fields = line.split_whitespace();
+    let owner = fields.next().ok_or("missing owner")?;
+    let owner = Name::from_ascii(owner).map_err(|e| e.to_string())?;
+    if !owner.is_fqdn() {
+        return Err("owner must be fully qualified".to_string());
+    }
+    // skip ttl and class
+    let record_type = fields
+        .find(|field| field.parse::<u32>().is_err() && !field.eq_ignore_ascii_case("IN"))
+        .ok_or("missing record type")?;
+    let mut number = |name: &str| -> Result<u16, String> {
+        fields
+            .next()
+            .and_then(|field| field.parse().ok())
+            .ok_or(format!("invalid {}", name))
+    };
+    if record_type.eq_ignore_ascii_case("DS") {
+        let key_tag = number("key tag")?;
+        let algorithm = Algorithm::from_u8(number("algorithm")? as u8);
+        let digest_type =
+            DigestType::from_u8(number("digest type")? as u8).map_err(|e| e.to_string())?;
+        let digest = data_encoding::HEXUPPER_PERMISSIVE
+            .decode(fields.collect::<String>().as_bytes())
+            .map_err(|e| format!("invalid digest: {}", e))?;
+        Ok((
+            owner,
+            AnchorKey::Ds(DS::new(key_tag, algorithm, digest_type, digest)),
+        ))
+    } else if record_type.eq_ignore_ascii_case("DNSKEY") {
+        let flags = number("flags")?;
+        if number("protocol")? != 3 {
+            return Err("protocol must be 3".to_string());
+        }
+        let algorithm = Algorithm::from_u8(number("algorithm")? as u8);
+        let key = data_encoding::BASE64
+            .decode(fields.collect::<String>().as_bytes())
+            .map_err(|e| format!("invalid public key: {}", e))?;
+        Ok((
+            owner,
+            AnchorKey::Dnskey(DNSKEY::new(
+                flags & 0x0100 != 0,
+                flags & 0x0001 != 0,
+                flags & 0x0080 != 0,
+                algorithm,
+                key,
+            )),
+        ))
+    } else {
+        Err(format!("unsupported record type {}", record_type))
+    }
+}
+
+// Outcome of the verification of a single RRset.
+enum Verdict {
+    // signed by an authenticated key, with the label count of the RRSIG
+    // if the RRset was expanded from a wildcard
+    Secure(Option<u8>),
+    // signed by a zone below an unsigned delegation
+    Insecure,
+    // no RRSIG at all
+    Unsigned,
+    Bogus(String),
+}
+
+// Authenticated DNSKEYs of a zone kept by the validator.
+struct CachedKeys {
+    keys: Vec<DNSKEY>,
+    expires: Instant,
+}
+
+// DNSKEYs of a zone.
+enum ZoneKeys {
+    Secure(Vec<DNSKEY>),
+    Insecure,
+    Bogus(String),
+}
+
+// What the NSEC or NSEC3 records of a negative answer prove.
+#[derive(Debug, PartialEq, Eq)]
+enum Proof {
+    // the name exists without the queried type, delegation is set for
+    // names with NS but without SOA records, i.e. zone cuts
+    NoData { delegation: bool },
+    NxDomain,
+    // the name is in an NSEC3 opt-out span and may be an unsigned
+    // delegation, see RFC 5155 section 6
+    OptOut,
+    // NSEC3 records too expensive to check
+    Insecure,
+    None,
+}
+
+// Validated NSEC and NSEC3 records of an answer.
+#[derive(Default)]
+struct Denial {
+    nsec: Vec<(Name, NSEC)>,
+    nsec3: Vec<(Name, NSEC3)>,
+}
+
+// Outcome of the validation of the denial records of an answer.
+enum DenialVerdict {
+    Secure(Denial),
+    Insecure,
+    Unsigned,
+    Bogus(String),
+}
+
+// A set of records with the same owner and type and the RRSIGs covering it.
+struct Rrset {
+    name: Name,
+    record_type: RecordType,
+    records: Vec<Record>,
+    rrsigs: Vec<SIG>,
+}
+
+pub struct Validator {
+    anchors: TrustAnchors,
+    keys: Mutex<HashMap<Name, CachedKeys>>, // authenticated DNSKEYs by zone
+}
+
+impl Validator {
+    pub fn new(anchors: TrustAnchors) -> Validator {
+        Validator {
+            anchors,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Security of resp, the answer of an upstream resolver received with
+    // the DO bit, with the records needed for validation taken from lookup.
+    pub async fn validate<L: Lookup + Sync>(&self, lookup: &L, resp: &Message) -> Security {
+        let query = match resp.queries().first() {
+            Some(query) => query,
+            None => return Security::Insecure,
+        };
+        let (qname, qtype) = (query.name(), query.query_type());
+        let nxdomain = match resp.response_code() {
+            ResponseCode::NoError => false,
+            ResponseCode::NXDomain => true,
+            _ => return Security::Insecure,
+        };
+        if qtype == RRSIG || self.anchors.closest(qname).is_none() {
+            return Security::Insecure;
+        }
+
+        let answers = rrsets(resp.answers());
+        let mut security = Security::Secure;
+        let mut denial: Option<Denial> = None;
+        for rrset in &answers {
+            match self.verify_rrset(lookup, rrset, 0).await {
+                Verdict::Secure(None) => {}
+                Verdict::Secure(Some(labels)) => {
+                    // the name itself must not exist, see RFC 4035 section 5.3.4
+                    if denial.is_none() {
+                        denial = match self.verify_denial(lookup, resp, 0).await {
+                            DenialVerdict::Secure(denial) => Some(denial),
+                            DenialVerdict::Bogus(reason) => return Security::Bogus(reason),
+                            _ => Some(Denial::default()),
+                        };
+                    }
+                    if !denial
+                        .as_ref()
+                        .map_or(false, |d| proves_expansion(d, &rrset.name, labels))
+                    {
+                        return Security::Bogus(format!(
+                            "no proof for wildcard expansion of {}",
+                            rrset.name
+                        ));
+                    }
+                }
+                Verdict::Insecure => security = security.and(Security::Insecure),
+                Verdict::Unsigned if synthesized(rrset, &answers) => {}
+                Verdict::Unsigned => {
+                    security = security.and(self.prove_insecure(lookup, &rrset.name, 0).await)
+                }
+                Verdict::Bogus(reason) => return Security::Bogus(reason),
+            }
+            if let Security::Bogus(_) = security {
+                return security;
+            }
+        }
+
+        // follow the CNAME chain to see if there is any data for the query
+        let mut target = qname.clone();
+        for _ in 0..answers.len() {
+            match answers
+                .iter()
+                .find(|rrset| rrset.name == target && rrset.record_type == RecordType::CNAME)
+            {
+                Some(rrset) if qtype != RecordType::CNAME => match rrset.records[0].rdata() {
+                    RData::CNAME(next) => target = next.clone(),
+                    _ => break,
+                },
+                _ => break,
+            }
+        }
+        let positive = answers.iter().any(|rrset| {
+            rrset.name == target && (rrset.record_type == qtype || qtype == RecordType::ANY)
+        });
+        if positive && !nxdomain {
+            return security;
+        }
+
+        let proof = match self.verify_denial(lookup, resp, 0).await {
+            DenialVerdict::Secure(denial) => prove(&denial, &target, qtype),
+            DenialVerdict::Insecure => return security.and(Security::Insecure),
+            DenialVerdict::Unsigned => {
+                return security.and(self.prove_insecure(lookup, &target, 0).await)
+            }
+            DenialVerdict::Bogus(reason) => return Security::Bogus(reason),
+        };
+        match (proof, nxdomain) {
+            (Proof::NxDomain, true) | (Proof::NoData { .. }, false) => security,
+            (Proof::OptOut, _) | (Proof::Insecure, _) => security.and(Security::Insecure),
+            _ => Security::Bogus(format!("no proof of nonexistence for {} {}", target, qtype)),
+        }
+    }
+
+    // Check the RRSIGs of rrset with the keys of their signer.
+    fn verify_rrset<'a, L: Lookup + Sync>(
+        &'a self,
+        lookup: &'a L,
+        rrset: &'a Rrset,
+        depth: usize,
+    ) -> BoxFuture<'a, Verdict> {
+        Box::pin(async move {
+            if rrset.rrsigs.is_empty() {
+                return Verdict::Unsigned;
+            }
+            let anchor = match self.anchors.closest(&rrset.name) {
+                Some(anchor) => anchor,
+                None => return Verdict::Insecure,
+            };
+            let mut reason = format!("no valid RRSIG for {} {}", rrset.name, rrset.record_type);
+            for sig in &rrset.rrsigs {
+                let signer = sig.signer_name();
+                // the signer must be the zone of the RRset, which for a DS
+                // RRset is the parent zone, and at or below the anchor
+                if !signer.zone_of(&rrset.name)
+                    || !anchor.zone_of(signer)
+                    || (rrset.record_type == TYPE_DS && *signer == rrset.name)
+                    || sig.num_labels() > rrset.name.num_labels()
+                    || !valid_now(sig)
+                {
+                    continue;
+                }
+                let keys = match self.zone_keys(lookup, signer.clone(), depth + 1).await {
+                    ZoneKeys::Secure(keys) => keys,
+                    ZoneKeys::Insecure => return Verdict::Insecure,
+                    ZoneKeys::Bogus(bogus) => {
+                        reason = bogus;
+                        continue;
+                    }
+                };
+                if keys.iter().any(|key| verifies(key, sig, rrset)) {
+                    let expanded = sig.num_labels() < rrset.name.num_labels();
+                    return Verdict::Secure(expanded.then(|| sig.num_labels()));
+                }
+            }
+            Verdict::Bogus(reason)
+        })
+    }
+
+    // Authenticated DNSKEYs of zone, insecure if zone is below an unsigned
+    // delegation.
+    fn zone_keys<'a, L: Lookup + Sync>(
+        &'a self,
+        lookup: &'a L,
+        zone: Name,
+        depth: usize,
+    ) -> BoxFuture<'a, ZoneKeys> {
+        Box::pin(async move {
+            if depth > MAX_CHAIN_DEPTH {
+                return ZoneKeys::Bogus(format!("chain of trust for {} too long", zone));
+            }
+            let anchor = match self.anchors.closest(&zone) {
+                Some(anchor) => anchor.clone(),
+                None => return ZoneKeys::Insecure,
+            };
+            if let Some(keys) = self.cached_keys(&zone) {
+                return ZoneKeys::Secure(keys);
+            }
+
+            // seconds until the DS records, if any, expire
+            let mut lifetime = u32::MAX;
+            let (ds, anchored): (Vec<DS>, Vec<DNSKEY>) = if zone == anchor {
+                let mut ds = Vec::new();
+                let mut anchored = Vec::new();
+                for key in self.anchors.keys(&zone) {
+                    match key {
+                        AnchorKey::Ds(d) => ds.push(d.clone()),
+                        AnchorKey::Dnskey(k) => anchored.push(k.clone()),
+                    }
+                }
+                (ds, anchored)
+            } else {
+                match self.ds_set(lookup, &zone, depth).await {
+                    ZoneDs::Secure(ds, ds_lifetime) => {
+                        lifetime = ds_lifetime;
+                        (ds, Vec::new())
+                    }
+                    ZoneDs::Insecure => return ZoneKeys::Insecure,
+                    ZoneDs::Bogus(reason) => return ZoneKeys::Bogus(reason),
+                }
+            };
+
+            let resp = match lookup.lookup(&zone, TYPE_DNSKEY).await {
+                Some(resp) => resp,
+                None => return ZoneKeys::Bogus(format!("unable to look up DNSKEY of {}", zone)),
+            };
+            let rrset = match rrsets(resp.answers())
+                .into_iter()
+                .find(|rrset| rrset.name == zone && rrset.record_type == TYPE_DNSKEY)
+            {
+                Some(rrset) => rrset,
+                None => return ZoneKeys::Bogus(format!("no DNSKEY records for {}", zone)),
+            };
+            let keys: Vec<DNSKEY> = rrset
+                .records
+                .iter()
+                .filter_map(|record| match record.rdata() {
+                    RData::DNSSEC(DNSSECRData::DNSKEY(key)) if key.zone_key() && !key.revoke() => {
+                        Some(key.clone())
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            // keys vouched for by the parent or the anchor
+            let trusted: Vec<&DNSKEY> = keys
+                .iter()
+                .filter(|key| {
+                    anchored.iter().any(|anchor| anchor == *key)
+                        || ds.iter().any(|d| {
+                            d.algorithm() == key.algorithm()
+                                && d.covers(&zone, key).unwrap_or(false)
+                        })
+                })
+                .collect();
+            if trusted.is_empty() {
+                // zones signed with algorithms the validator does not know
+                // are treated as unsigned, see RFC 4035 section 5.2
+                if anchored.is_empty() && ds.iter().all(|d| !supported(d.algorithm())) {
+                    return ZoneKeys::Insecure;
+                }
+                return ZoneKeys::Bogus(format!("no DNSKEY of {} matches its DS records", zone));
+            }
+
+            let signed = rrset.rrsigs.iter().any(|sig| {
+                *sig.signer_name() == zone
+                    && valid_now(sig)
+                    && trusted.iter().any(|key| verifies(key, sig, &rrset))
+            });
+            if !signed {
+                return ZoneKeys::Bogus(format!(
+                    "DNSKEY records of {} are not signed by a trusted key",
+                    zone
+                ));
+            }
+            debug!("Authenticated {} DNSKEY records of {}", keys.len(), zone);
+            self.cache_keys(&zone, &keys, lifetime.min(rrset_lifetime(&rrset)));
+            ZoneKeys::Secure(keys)
+        })
+    }
+
+    // Authenticated DNSKEYs of zone which have not expired yet.
+    fn cached_keys(&self, zone: &Name) -> Option<Vec<DNSKEY>> {
+        let keys = match self.keys.lock() {
+            Ok(keys) => keys,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        keys.get(zone)
+            .filter(|cached| cached.expires > Instant::now())
+            .map(|cached| cached.keys.clone())
+    }
+
+    // Keep the authenticated DNSKEYs of zone for lifetime seconds.
+    fn cache_keys(&self, zone: &Name, keys: &[DNSKEY], lifetime: u32) {
+        if lifetime == 0 {
+            return;
+        }
+        let mut cached = match self.keys.lock() {
+            Ok(keys) => keys,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = Instant::now();
+        if cached.len() >= MAX_CACHED_ZONES {
+            cached.retain(|_, keys| keys.expires > now);
+            if cached.len() >= MAX_CACHED_ZONES {
+                cached.clear();
+            }
+        }
+        cached.insert(
+            zone.clone(),
+            CachedKeys {
+                keys: keys.to_vec(),
+                expires: now + Duration::from_secs(lifetime.into()),
+            },
+        );
+    }
+
+    // Authenticated DS records of zone, insecure if the parent proves there
+    // are none.
+    async fn ds_set<L: Lookup + Sync>(&self, lookup: &L, zone: &Name, depth: usize) -> ZoneDs {
+        let resp = match lookup.lookup(zone, TYPE_DS).await {
+            Some(resp) => resp,
+            None => return ZoneDs::Bogus(format!("unable to look up DS of {}", zone)),
+        };
+        if let Some(rrset) = rrsets(resp.answers())
+            .into_iter()
+            .find(|rrset| rrset.name == *zone && rrset.record_type == TYPE_DS)
+        {
+            return match self.verify_rrset(lookup, &rrset, depth).await {
+                Verdict::Secure(_) => ZoneDs::Secure(
+                    rrset
+                        .records
+                        .iter()
+                        .filter_map(|record| match record.rdata() {
+                            RData::DNSSEC(DNSSECRData::DS(ds)) => Some(ds.clone()),
+                            _ => None,
+                        })
+                        .collect(),
+                    rrset_lifetime(&rrset),
+                ),
+                Verdict::Insecure => ZoneDs::Insecure,
+                Verdict::Unsigned => match self.prove_insecure(lookup, zone, depth).await {
+                    Security::Insecure => ZoneDs::Insecure,
+                    _ => ZoneDs::Bogus(format!("DS records of {} are not signed", zone)),
+                },
+                Verdict::Bogus(reason) => ZoneDs::Bogus(reason),
+            };
+        }
+
+        match self.verify_denial(lookup, &resp, depth).await {
+            DenialVerdict::Secure(denial) => match prove(&denial, zone, TYPE_DS) {
+                Proof::NoData { delegation: true } | Proof::OptOut | Proof::Insecure => {
+                    ZoneDs::Insecure
+                }
+                proof => ZoneDs::Bogus(format!("no delegation to {} ({:?})", zone, proof)),
+            },
+            DenialVerdict::Insecure => ZoneDs::Insecure,
+            DenialVerdict::Unsigned => match self.prove_insecure(lookup, zone, depth).await {
+                Security::Insecure => ZoneDs::Insecure,
+                _ => ZoneDs::Bogus(format!("denial of DS for {} is not signed", zone)),
+            },
+            DenialVerdict::Bogus(reason) => ZoneDs::Bogus(reason),
+        }
+    }
+
+    // Validate the NSEC, NSEC3 and SOA records in the authority section of
+    // resp.
+    async fn verify_denial<L: Lookup + Sync>(
+        &self,
+        lookup: &L,
+        resp: &Message,
+        depth: usize,
+    ) -> DenialVerdict {
+        let mut denial = Denial::default();
+        let mut secure = false;
+        let mut insecure = false;
+        for rrset in rrsets(resp.name_servers())
+            .iter()
+            .filter(|rrset| matches!(rrset.record_type, RecordType::SOA | TYPE_NSEC | TYPE_NSEC3))
+        {
+            match self.verify_rrset(lookup, rrset, depth).await {
+                Verdict::Secure(_) => secure = true,
+                Verdict::Insecure => {
+                    insecure = true;
+                    continue;
+                }
+                Verdict::Unsigned => continue,
+                Verdict::Bogus(reason) => return DenialVerdict::Bogus(reason),
+            }
+            for record in &rrset.records {
+                match record.rdata() {
+                    RData::DNSSEC(DNSSECRData::NSEC(nsec)) => {
+                        denial.nsec.push((record.name().clone(), nsec.clone()))
+                    }
+                    RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => {
+                        denial.nsec3.push((record.name().clone(), nsec3.clone()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if insecure {
+            DenialVerdict::Insecure
+        } else if secure {
+            DenialVerdict::Secure(denial)
+        } else {
+            DenialVerdict::Unsigned
+        }
+    }
+
+    // Security of unsigned data for name: insecure if there is a delegation
+    // without DS records between the trust anchor and name, bogus otherwise.
+    fn prove_insecure<'a, L: Lookup + Sync>(
+        &'a self,
+        lookup: &'a L,
+        name: &'a Name,
+        depth: usize,
+    ) -> BoxFuture<'a, Security> {
+        Box::pin(async move {
+            if depth > MAX_CHAIN_DEPTH {
+                return Security::Bogus(format!("chain of trust for {} too long", name));
+            }
+            let anchor = match self.anchors.closest(name) {
+                Some(anchor) => anchor,
+                None => return Security::Insecure,
+            };
+            for labels in anchor.num_labels() + 1..=name.num_labels() {
+                let zone = name.trim_to(labels as usize);
+                let resp = match lookup.lookup(&zone, TYPE_DS).await {
+                    Some(resp) => resp,
+                    None => return Security::Bogus(format!("unable to look up DS of {}", zone)),
+                };
+                if let Some(rrset) = rrsets(resp.answers())
+                    .into_iter()
+                    .find(|rrset| rrset.name == zone && rrset.record_type == TYPE_DS)
+                {
+                    // a signed delegation, continue below it
+                    match self.verify_rrset(lookup, &rrset, depth + 1).await {
+                        Verdict::Secure(_) => continue,
+                        Verdict::Insecure => return Security::Insecure,
+                        Verdict::Unsigned => {
+                            return Security::Bogus(format!(
+                                "DS records of {} are not signed",
+                                zone
+                            ))
+                        }
+                        Verdict::Bogus(reason) => return Security::Bogus(reason),
+                    }
+                }
+                match self.verify_denial(lookup, &resp, depth + 1).await {
+                    DenialVerdict::Secure(denial) => match prove(&denial, &zone, TYPE_DS) {
+                        Proof::NoData { delegation: true } | Proof::OptOut | Proof::Insecure => {
+                            debug!("Unsigned delegation to {}", zone);
+                            return Security::Insecure;
+                        }
+                        // no zone cut here, the name is still in the signed zone above
+                        Proof::NoData { delegation: false } => continue,
+                        _ => break,
+                    },
+                    DenialVerdict::Insecure => return Security::Insecure,
+                    DenialVerdict::Unsigned => {
+                        return Security::Bogus(format!("denial of DS for {} is not signed", zone))
+                    }
+                    DenialVerdict::Bogus(reason) => return Security::Bogus(reason),
+                }
+            }
+            Security::Bogus(format!("unsigned records for {} in a signed zone", name))
+        })
+    }
+}
+
+// DS records of a zone, secure ones with the seconds until they expire.
+enum ZoneDs {
+    Secure(Vec<DS>, u32),
+    Insecure,
+    Bogus(String),
+}
+
+// Seconds rrset may be kept: the lowest ttl of its records and RRSIGs,
+// but no longer than any of the RRSIGs is valid.
+fn rrset_lifetime(rrset: &Rrset) -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0);
+    let records = rrset.records.iter().map(Record::ttl);
+    let sigs = rrset.rrsigs.iter().map(|sig| {
+        let valid = sig.sig_expiration().wrapping_sub(now) as i32;
+        sig.original_ttl().min(valid.max(0) as u32)
+    });
+    records.chain(sigs).min().unwrap_or(0)
+}
+
+// Group records into RRsets with their RRSIGs, in the order of the first
+// record of every RRset.
+fn rrsets(records: &[Record]) -> Vec<Rrset> {
+    let mut sets: Vec<Rrset> = Vec::new();
+    for record in records.iter().filter(|record| record.rr_type() != RRSIG) {
+        match sets
+            .iter_mut()
+            .find(|set| set.name == *record.name() && set.record_type == record.rr_type())
+        {
+            Some(set) => set.records.push(record.clone()),
+            None => sets.push(Rrset {
+                name: record.name().clone(),
+                record_type: record.rr_type(),
+                records: vec![record.clone()],
+                rrsigs: Vec::new(),
+            }),
+        }
+    }
+    for record in records.iter().filter(|record| record.rr_type() == RRSIG) {
+        if let RData::DNSSEC(DNSSECRData::SIG(sig)) = record.rdata() {
+            if let Some(set) = sets
+                .iter_mut()
+                .find(|set| set.name == *record.name() && set.record_type == sig.type_covered())
+            {
+                set.rrsigs.push(sig.clone());
+            }
+        }
+    }
+    sets
+}
+
+// Whether rrset is a CNAME synthesized from a DNAME of answers, which is
+// never signed, see RFC 6672 section 5.3.1.
+fn synthesized(rrset: &Rrset, answers: &[Rrset]) -> bool {
+    rrset.record_type == RecordType::CNAME
+        && answers.iter().any(|dname| {
+            dname.record_type == DNAME
+                && dname.name != rrset.name
+                && dname.name.zone_of(&rrset.name)
+                && !dname.rrsigs.is_empty()
+        })
+}
+
+fn supported(algorithm: Algorithm) -> bool {
+    !matches!(algorithm, Algorithm::Unknown(_))
+}
+
+// Whether the validity period of sig includes the current time, compared
+// with serial number arithmetic, see RFC 4034 section 3.1.5.
+fn valid_now(sig: &SIG) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0);
+    (now.wrapping_sub(sig.sig_inception()) as i32) >= 0
+        && (sig.sig_expiration().wrapping_sub(now) as i32) >= 0
+}
+
+fn verifies(key: &DNSKEY, sig: &SIG, rrset: &Rrset) -> bool {
+    key.algorithm() == sig.algorithm()
+        && key.calculate_key_tag().ok() == Some(sig.key_tag())
+        && key
+            .verify_rrsig(
+                &rrset.name,
+                rrset.records[0].dns_class(),
+                sig,
+                &rrset.records,
+            )
+            .is_ok()
+}
+
+// What denial proves about the query for name and record_type.
+fn prove(denial: &Denial, name: &Name, record_type: RecordType) -> Proof {
+    if !denial.nsec.is_empty() {
+        prove_nsec(&denial.nsec, name, record_type)
+    } else if !denial.nsec3.is_empty() {
+        prove_nsec3(&denial.nsec3, name, record_type)
+    } else {
+        Proof::None
+    }
+}
+
+// Whether the types of an NSEC or NSEC3 record deny record_type at its
+// owner. Records of a delegation only speak for the DS records there, the
+// child zone is authoritative for everything else, see RFC 6840 section 4.1.
+fn denies_type(types: &[RecordType], record_type: RecordType) -> Option<Proof> {
+    let delegation = types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA);
+    if types.contains(&record_type)
+        || types.contains(&RecordType::CNAME)
+        || (delegation && record_type != TYPE_DS)
+    {
+        return None;
+    }
+    Some(Proof::NoData { delegation })
+}
+
+fn prove_nsec(nsecs: &[(Name, NSEC)], name: &Name, record_type: RecordType) -> Proof {
+    if let Some((_, nsec)) = nsecs.iter().find(|(owner, _)| owner == name) {
+        return denies_type(nsec.type_bit_maps(), record_type).unwrap_or(Proof::None);
+    }
+    let covering = match nsecs
+        .iter()
+        .find(|(owner, nsec)| nsec_covers(owner, nsec, name))
+    {
+        Some(covering) => covering,
+        None => return Proof::None,
+    };
+    // names between the covering NSEC and a descendant of name exist
+    // without any records, see RFC 4035 section 3.1.3.2
+    let next = covering.1.next_domain_name();
+    if name.zone_of(next) && name != next {
+        return Proof::NoData { delegation: false };
+    }
+    // the wildcard at the closest encloser must not exist either
+    let (before, after) = (
+        common_ancestor(name, &covering.0),
+        common_ancestor(name, next),
+    );
+    let encloser = if after.num_labels() > before.num_labels() {
+        after
+    } else {
+        before
+    };
+    let wildcard = match wildcard(&encloser) {
+        Some(wildcard) => wildcard,
+        None => return Proof::None,
+    };
+    if let Some((_, nsec)) = nsecs.iter().find(|(owner, _)| *owner == wildcard) {
+        return denies_type(nsec.type_bit_maps(), record_type).unwrap_or(Proof::None);
+    }
+    if nsecs
+        .iter()
+        .any(|(owner, nsec)| nsec_covers(owner, nsec, &wildcard))
+    {
+        Proof::NxDomain
+    } else {
+        Proof::None
+    }
+}
+
+// Whether the NSEC record of owner proves that name does not exist.
+fn nsec_covers(owner: &Name, nsec: &NSEC, name: &Name) -> bool {
+    let types = nsec.type_bit_maps();
+    // nothing below a delegation or DNAME is proven by the zone above
+    if owner.zone_of(name)
+        && (types.contains(&DNAME)
+            || (types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA)))
+    {
+        return false;
+    }
+    let next = nsec.next_domain_name();
+    if owner < next {
+        owner < name && name < next
+    } else {
+        // the last NSEC of the zone points back to the apex
+        owner < name && next.zone_of(name)
+    }
+}
+
+fn prove_nsec3(nsec3s: &[(Name, NSEC3)], name: &Name, record_type: RecordType) -> Proof {
+    if nsec3s
+        .iter()
+        .any(|(_, nsec3)| nsec3.iterations() > MAX_NSEC3_ITERATIONS)
+    {
+        return Proof::Insecure;
+    }
+    if let Some(nsec3) = nsec3_matching(nsec3s, name) {
+        return denies_type(nsec3.type_bit_maps(), record_type).unwrap_or(Proof::None);
+    }
+    let (encloser, covering) = match closest_encloser(nsec3s, name) {
+        Some(proof) => proof,
+        None => return Proof::None,
+    };
+    if record_type == TYPE_DS && covering.opt_out() {
+        return Proof::OptOut;
+    }
+    let wildcard = match wildcard(&encloser) {
+        Some(wildcard) => wildcard,
+        None => return Proof::None,
+    };
+    if let Some(nsec3) = nsec3_matching(nsec3s, &wildcard) {
+        return denies_type(nsec3.type_bit_maps(), record_type).unwrap_or(Proof::None);
+    }
+    if nsec3_covering(nsec3s, &wildcard).is_some() {
+        if covering.opt_out() {
+            Proof::OptOut
+        } else {
+            Proof::NxDomain
+        }
+    } else {
+        Proof::None
+    }
+}
+
+// Closest encloser proof of RFC 5155 section 8.3: the closest ancestor of
+// name which exists and the NSEC3 record covering the next closer name.
+fn closest_encloser<'a>(nsec3s: &'a [(Name, NSEC3)], name: &Name) -> Option<(Name, &'a NSEC3)> {
+    for labels in (0..name.num_labels()).rev() {
+        let encloser = name.trim_to(labels as usize);
+        let nsec3 = match nsec3_matching(nsec3s, &encloser) {
+            Some(nsec3) => nsec3,
+            None => continue,
+        };
+        let types = nsec3.type_bit_maps();
+        if types.contains(&DNAME)
+            || (types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA))
+        {
+            return None;
+        }
+        let next_closer = name.trim_to(labels as usize + 1);
+        return nsec3_covering(nsec3s, &next_closer).map(|covering| (encloser, covering));
+    }
+    None
+}
+
+// Hash of name with the parameters of nsec3, compared with NSEC3 owner
+// names of the same zone only.
+fn nsec3_hash(owner: &Name, nsec3: &NSEC3, name: &Name) -> Option<(Vec<u8>, Vec<u8>)> {
+    if !owner.base_name().zone_of(name) {
+        return None;
+    }
+    let label = owner.iter().next()?;
+    let owner_hash = data_encoding::BASE32_DNSSEC.decode(label).ok()?;
+    let hash = match nsec3.hash_algorithm() {
+        Nsec3HashAlgorithm::SHA1 => Nsec3HashAlgorithm::SHA1
+            .hash(nsec3.salt(), name, nsec3.iterations())
+            .ok()?,
+    };
+    Some((owner_hash, hash.as_ref().to_vec()))
+}
+
+fn nsec3_matching<'a>(nsec3s: &'a [(Name, NSEC3)], name: &Name) -> Option<&'a NSEC3> {
+    nsec3s.iter().find_map(|(owner, nsec3)| {
+        let (owner_hash, hash) = nsec3_hash(owner, nsec3, name)?;
+        (owner_hash == hash).then(|| nsec3)
+    })
+}
+
+fn nsec3_covering<'a>(nsec3s: &'a [(Name, NSEC3)], name: &Name) -> Option<&'a NSEC3> {
+    nsec3s.iter().find_map(|(owner, nsec3)| {
+        let (owner_hash, hash) = nsec3_hash(owner, nsec3, name)?;
+        let next = nsec3.next_hashed_owner_name();
+        let covers = if owner_hash.as_slice() < next {
+            owner_hash < hash && hash.as_slice() < next
+        } else {
+            // the last NSEC3 of the zone wraps around
+            owner_hash < hash || hash.as_slice() < next
+        };
+        covers.then(|| nsec3)
+    })
+}
+
+// Whether denial proves that name, which was answered from a wildcard
+// with labels labels, does not exist itself.
+fn proves_expansion(denial: &Denial, name: &Name, labels: u8) -> bool {
+    if !denial.nsec.is_empty() {
+        denial
+            .nsec
+            .iter()
+            .any(|(owner, nsec)| nsec_covers(owner, nsec, name))
+    } else {
+        let next_closer = name.trim_to(labels as usize + 1);
+        nsec3_covering(&denial.nsec3, &next_closer).is_some()
+    }
+}
+
+fn common_ancestor(a: &Name, b: &Name) -> Name {
+    let mut labels = a.num_labels().min(b.num_labels()) as usize;
+    while labels > 0 && a.trim_to(labels) != b.trim_to(labels) {
+        labels -= 1;
+    }
+    a.trim_to(labels)
+}
+
+// Wildcard directly below encloser.
+fn wildcard(encloser: &Name) -> Option<Name> {
+    Name::from_labels(vec![b"*" as &[u8]])
+        .map(|star| star.append_name(encloser))
+        .ok()
+}
@@ -0,0 +1,139 @@
+//! Coalescing of identical forwarded queries.
+//!
+//! Containers started together tend to resolve the same names at the same
+//! time. While a question is being forwarded to a set of upstream resolvers
+//! further queries with the same question, i.e. everything which would hit
+//! the same cache entry, wait for its answer instead of sending their own.
+//! The answer is handed to every waiting query with its own id. Like the
+//! cache the table is shared by all listeners and safe to use from any
+//! runtime.
+use crate::dns::cache::{answer_to, cache_key, CacheKey};
+use crate::dns::upstream::Nameserver;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+use trust_dns_proto::op::Message;
+
+#[derive(Default)]
+pub struct InFlight {
+    // questions being forwarded with the queries waiting for their answer
+    pending: Mutex<HashMap<CacheKey, Vec<oneshot::Sender<Message>>>>,
+}
+
+// Role of a query in the forwarding of its question.
+pub enum Flight<'a> {
+    // no identical question is in flight, the query must be forwarded
+    Lead(Leader<'a>),
+    // the question is already forwarded, wait for its answer
+    Follow(Follower),
+}
+
+pub struct Leader<'a> {
+    inflight: &'a InFlight,
+    key: Option<CacheKey>, // None for queries which are never coalesced
+}
+
+pub struct Follower {
+    answer: oneshot::Receiver<Message>,
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        InFlight::default()
+    }
+
+    // Join the forwarding of the question of req to upstreams.
+    pub fn join(&self, req: &Message, upstreams: &[Nameserver]) -> Flight<'_> {
+        let key = match cache_key(req, upstreams) {
+            Some(key) => key,
+            None => {
+                return Flight::Lead(Leader {
+                    inflight: self,
+                    key: None,
+                })
+            }
+        };
+        let mut pending = match self.pending.lock() {
+            Ok(pending) => pending,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match pending.get_mut(&key) {
+            Some(waiters) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Flight::Follow(Follower { answer: rx })
+            }
+            None => {
+                pending.insert(key.clone(), Vec::new());
+                Flight::Lead(Leader {
+                    inflight: self,
+                    key: Some(key),
+                })
+            }
+        }
+    }
+
+    // Number of questions in flight.
+    pub fn len(&self) -> usize {
+        match self.pending.lock() {
+            Ok(pending) => pending.len(),
+            Err(poisoned) => poisoned.into_inner().len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn finish(&self, key: &CacheKey) -> Vec<oneshot::Sender<Message>> {
+        let mut pending = match self.pending.lock() {
+            Ok(pending) => pending,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        pending.remove(key).unwrap_or_default()
+    }
+}
+
+impl Leader<'_> {
+    // Hand resp, the answer to the question, to all waiting queries.
+    pub fn complete(mut self, resp: &Message) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        let waiters = self.inflight.finish(&key);
+        if !waiters.is_empty() {
+            debug!(
+                "[{}] answering {} coalesced queries",
+                resp.id(),
+                waiters.len()
+            );
+        }
+        for waiter in waiters {
+            // the waiting query may be gone already
+            let _ = waiter.send(resp.clone());
+        }
+    }
+}
+
+impl Drop for Leader<'_> {
+    // A leader dropped without answer, e.g. as its listener was stopped,
+    // releases the question so the waiting queries forward it on their own.
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.inflight.finish(&key);
+        }
+    }
+}
+
+impl Follower {
+    // Answer of the leading query turned into the answer of req, None if
+    // the leading query gave up without answer.
+    pub async fn wait(self, req: &Message) -> Option<Message> {
+        let mut resp = self.answer.await.ok()?;
+        answer_to(&mut resp, req);
+        debug!("[{}] answered by coalesced query", req.id());
+        Some(resp)
+    }
+}
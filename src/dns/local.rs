@@ -0,0 +1,161 @@
+//! Special-use and locally-served zones answered by aardvark itself.
+//!
+//! Names of these zones never exist in the global DNS, forwarding queries
+//! for them only leaks internal names to upstream resolvers and delays the
+//! inevitable failure. Every zone is served as an empty zone with just its
+//! apex, i.e. other names get NXDOMAIN, as described in RFC 6303. Names of
+//! loopback zones like localhost. resolve to the loopback addresses instead,
+//! see RFC 6761 section 6.3.
+//!
+//! The built-in reverse zones of private addresses are the exception: they
+//! are served by the resolvers of many corporate networks, so they are only
+//! answered locally for requesters without dns= servers of their own.
+use crate::dns::zone::Zone;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use trust_dns_client::rr::Name;
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::{DNSClass, RData, Record, RecordType};
+
+// Special-use domains of RFC 6761 and RFC 7686 and the locally-served
+// reverse zones of RFC 6303 for loopback, link-local and documentation
+// addresses. The reverse zones of :: and ::1 are added by builtin().
+const BUILTIN_LOCAL_ZONES: &str = "localhost=loopback,invalid,test,local,onion,\
+    0.in-addr.arpa,127.in-addr.arpa,254.169.in-addr.arpa,2.0.192.in-addr.arpa,100.51.198.in-addr.arpa,\
+    113.0.203.in-addr.arpa,255.255.255.255.in-addr.arpa,d.f.ip6.arpa,8.e.f.ip6.arpa,\
+    9.e.f.ip6.arpa,a.e.f.ip6.arpa,b.e.f.ip6.arpa,8.b.d.0.1.0.0.2.ip6.arpa";
+// Locally-served reverse zones of RFC 6303 for private addresses.
+const BUILTIN_PRIVATE_ZONES: &str = "10.in-addr.arpa,16.172.in-addr.arpa,\
+    17.172.in-addr.arpa,18.172.in-addr.arpa,19.172.in-addr.arpa,20.172.in-addr.arpa,\
+    21.172.in-addr.arpa,22.172.in-addr.arpa,23.172.in-addr.arpa,24.172.in-addr.arpa,\
+    25.172.in-addr.arpa,26.172.in-addr.arpa,27.172.in-addr.arpa,28.172.in-addr.arpa,\
+    29.172.in-addr.arpa,30.172.in-addr.arpa,31.172.in-addr.arpa,168.192.in-addr.arpa,\
+    d.f.ip6.arpa";
+
+#[derive(Clone, Debug)]
+struct LocalZone {
+    origin: Name,
+    loopback: bool, // names resolve to the loopback addresses
+    private: bool,  // built-in reverse zone of private addresses
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LocalZones {
+    zones: Vec<LocalZone>,
+}
+
+impl LocalZones {
+    // The special-use and locally-served zones every resolver should
+    // answer itself.
+    pub fn builtin() -> LocalZones {
+        let ip6_unspecified = format!("{}.ip6.arpa", ["0"; 32].join("."));
+        let ip6_loopback = format!("1.{}.ip6.arpa", ["0"; 31].join("."));
+        let mut zones = LocalZones::parse(&format!(
+            "{},{},{}",
+            BUILTIN_LOCAL_ZONES, ip6_unspecified, ip6_loopback
+        ))
+        .expect("invalid built-in local zones");
+        let private = LocalZones::parse(BUILTIN_PRIVATE_ZONES)
+            .expect("invalid built-in private zones")
+            .zones
+            .into_iter()
+            .map(|local| LocalZone {
+                private: true,
+                ..local
+            });
+        zones.zones.extend(private);
+        zones
+    }
+
+    // Parse a comma-separated list of zones, names of zones with a
+    // =loopback suffix resolve to the loopback addresses, e.g.
+    // `localhost=loopback,invalid`. An empty list answers nothing locally.
+    pub fn parse(list: &str) -> io::Result<LocalZones> {
+        let mut zones = Vec::new();
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (zone, loopback) = match entry.split_once('=') {
+                Some((zone, "loopback")) => (zone, true),
+                Some((_, kind)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown kind {} of local zone {}", kind, entry),
+                    ))
+                }
+                None => (entry, false),
+            };
+            let origin = Zone::new(zone, 1, 0)
+                .map(|zone| zone.origin().clone())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid local zone {}", zone),
+                    )
+                })?;
+            zones.push(LocalZone {
+                origin,
+                loopback,
+                private: false,
+            });
+        }
+        Ok(LocalZones { zones })
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    // Answer to req for name and record_type, None if name is not in any
+    // of the zones. Loopback addresses get ttl, the SOA of negative answers
+    // negative_ttl. The built-in private reverse zones are left to the
+    // upstream resolvers if the requester has its own, i.e. if
+    // own_upstreams is set.
+    pub fn answer(
+        &self,
+        name: &Name,
+        record_type: RecordType,
+        req: &Message,
+        ttl: u32,
+        negative_ttl: u32,
+        own_upstreams: bool,
+    ) -> Option<Message> {
+        // the most specific zone wins
+        let local = self
+            .zones
+            .iter()
+            .filter(|local| !(own_upstreams && local.private))
+            .filter(|local| local.origin.zone_of(name))
+            .max_by_key(|local| local.origin.num_labels())?;
+        let zone = Zone::new(&local.origin.to_string(), 1, negative_ttl)?;
+        let apex = name == zone.origin();
+
+        let mut resp = req.clone();
+        resp.set_authoritative(true);
+        let answer = match record_type {
+            RecordType::SOA if apex => Some(zone.soa_record()),
+            RecordType::NS if apex => Some(zone.ns_record(ttl)),
+            RecordType::A if local.loopback => Some(RData::A(Ipv4Addr::LOCALHOST))
+                .map(|rdata| Record::from_rdata(name.clone(), ttl, rdata)),
+            RecordType::AAAA if local.loopback => Some(RData::AAAA(Ipv6Addr::LOCALHOST))
+                .map(|rdata| Record::from_rdata(name.clone(), ttl, rdata)),
+            _ => None,
+        };
+        match answer {
+            Some(mut record) => {
+                record.set_dns_class(DNSClass::IN);
+                resp.add_answer(record);
+            }
+            None => {
+                // every name of a loopback zone exists
+                if !apex && !local.loopback {
+                    resp.set_response_code(ResponseCode::NXDomain);
+                }
+                resp.add_name_server(zone.soa_record());
+            }
+        }
+        Some(resp)
+    }
+}
@@ -1 +1,10 @@
+pub mod cache;
 pub mod coredns;
+pub mod dnssec;
+pub mod inflight;
+pub mod local;
+pub mod pool;
+pub mod resolv;
+pub mod tls;
+pub mod upstream;
+pub mod zone;
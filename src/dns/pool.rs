@@ -0,0 +1,219 @@
+//! Long-lived clients for upstream resolvers.
+//!
+//! Every listener runs its own tokio runtime which goes away on reload, so
+//! the pool owns a small runtime of its own which drives the background
+//! tasks of all clients. Clients are created on first use of a nameserver,
+//! shared by all queries and listeners and only rebuilt when they broke.
+//! The timeout a client is created with only bounds its own connection
+//! attempts and queries, callers enforce the deadline of every query on
+//! their own so that it can differ between queries without new clients.
+//! Clients of nameservers which are
+//! no longer used, e.g. after resolv.conf changed, are dropped after a while.
+//!
+//! A udp client still sends every query from a fresh random source port,
+//! which keeps answers hard to spoof, while tcp and tls clients keep their
+//! connection open and multiplex all queries over it. https clients do the
+//! same with the streams of a single HTTP/2 connection.
+use crate::dns::upstream::{Nameserver, Protocol};
+use log::debug;
+use rustls::ClientConfig;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::runtime::{Builder, Handle};
+use tokio::time::timeout as connect_timeout;
+use trust_dns_client::client::AsyncClient;
+use trust_dns_https::HttpsClientStreamBuilder;
+use trust_dns_proto::{
+    error::ProtoError, iocompat::AsyncIoTokioAsStd, tcp::TcpClientStream, udp::UdpClientStream,
+};
+use trust_dns_rustls::tls_client_connect;
+
+// Clients unused for this long are dropped.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+// ALPN protocol id of HTTP/2, the only HTTP version DoH is spoken over.
+const ALPN_H2: &[u8] = b"h2";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+struct PooledClient {
+    client: AsyncClient,
+    last_used: Instant,
+}
+
+pub struct ClientPool {
+    runtime: Handle, // runtime driving the background tasks of the clients
+    clients: Mutex<HashMap<(Nameserver, Transport), PooledClient>>,
+    tls_config: Arc<ClientConfig>,   // used by all tls clients
+    https_config: Arc<ClientConfig>, // tls_config offering HTTP/2 by ALPN
+}
+
+impl ClientPool {
+    pub fn new(tls_config: ClientConfig) -> std::io::Result<ClientPool> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let handle = runtime.handle().clone();
+        thread::Builder::new()
+            .name("upstream-pool".to_string())
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+        let mut https_config = tls_config.clone();
+        https_config.alpn_protocols = vec![ALPN_H2.to_vec()];
+        Ok(ClientPool {
+            runtime: handle,
+            clients: Mutex::new(HashMap::new()),
+            tls_config: Arc::new(tls_config),
+            https_config: Arc::new(https_config),
+        })
+    }
+
+    // Client for nameserver over transport, a new one is created with
+    // timeout if there is none in the pool yet.
+    pub async fn client(
+        &self,
+        nameserver: &Nameserver,
+        transport: Transport,
+        timeout: Duration,
+    ) -> Result<AsyncClient, ProtoError> {
+        let key = (nameserver.clone(), transport);
+        if let Some(client) = self.get(&key) {
+            return Ok(client);
+        }
+
+        debug!(
+            "Creating {:?} upstream client for {}",
+            transport, nameserver
+        );
+        let addr = nameserver.addr;
+        let server_name = match &nameserver.protocol {
+            Protocol::Tls { server_name } | Protocol::Https { server_name } => {
+                Some(server_name.clone())
+            }
+            Protocol::Dns => None,
+        };
+        let tls_config = self.tls_config.clone();
+        let https_config = self.https_config.clone();
+        let connect = self.runtime.spawn(async move {
+            let client = match transport {
+                Transport::Udp => {
+                    let stream = UdpClientStream::<UdpSocket>::with_timeout(addr, timeout);
+                    let (client, bg) = AsyncClient::connect(stream).await?;
+                    tokio::spawn(bg);
+                    client
+                }
+                Transport::Tcp => {
+                    let (stream, sender) =
+                        TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::with_timeout(
+                            addr, timeout,
+                        );
+                    let (client, bg) =
+                        AsyncClient::with_timeout(stream, sender, timeout, None).await?;
+                    tokio::spawn(bg);
+                    client
+                }
+                Transport::Tls => {
+                    let server_name = server_name.ok_or_else(|| {
+                        ProtoError::from(format!("no tls server name for {}", addr))
+                    })?;
+                    let (stream, sender) = tls_client_connect::<AsyncIoTokioAsStd<TcpStream>>(
+                        addr,
+                        server_name,
+                        tls_config,
+                    );
+                    let (client, bg) =
+                        AsyncClient::with_timeout(stream, Box::new(sender), timeout, None).await?;
+                    tokio::spawn(bg);
+                    client
+                }
+                Transport::Https => {
+                    let server_name = server_name.ok_or_else(|| {
+                        ProtoError::from(format!("no tls server name for {}", addr))
+                    })?;
+                    // unlike the other streams the https one has no timeout
+                    // of its own, queries are bounded by the caller
+                    let stream = HttpsClientStreamBuilder::with_client_config(https_config)
+                        .build::<AsyncIoTokioAsStd<TcpStream>>(addr, server_name);
+                    let (client, bg) =
+                        match connect_timeout(timeout, AsyncClient::connect(stream)).await {
+                            Ok(connected) => connected?,
+                            Err(_) => {
+                                return Err(ProtoError::from(format!(
+                                    "https connection to {} timed out",
+                                    addr
+                                )))
+                            }
+                        };
+                    tokio::spawn(bg);
+                    client
+                }
+            };
+            Ok::<AsyncClient, ProtoError>(client)
+        });
+        let client = match connect.await {
+            Ok(client) => client?,
+            Err(e) => {
+                return Err(ProtoError::from(format!(
+                    "upstream client task failed: {}",
+                    e
+                )))
+            }
+        };
+
+        let mut clients = match self.clients.lock() {
+            Ok(clients) => clients,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        clients.insert(
+            key,
+            PooledClient {
+                client: client.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(client)
+    }
+
+    // Drop the client of nameserver over transport so the next query
+    // builds a new one, e.g. after it failed for other reasons than a
+    // timeout or its connection was closed.
+    pub fn discard(&self, nameserver: &Nameserver, transport: Transport) {
+        let mut clients = match self.clients.lock() {
+            Ok(clients) => clients,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        clients.remove(&(nameserver.clone(), transport));
+    }
+
+    pub fn len(&self) -> usize {
+        match self.clients.lock() {
+            Ok(clients) => clients.len(),
+            Err(poisoned) => poisoned.into_inner().len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Pooled client for key, other idle clients are dropped on the way.
+    fn get(&self, key: &(Nameserver, Transport)) -> Option<AsyncClient> {
+        let mut clients = match self.clients.lock() {
+            Ok(clients) => clients,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = Instant::now();
+        clients.retain(|k, pooled| {
+            k == key || now.duration_since(pooled.last_used) < POOL_IDLE_TIMEOUT
+        });
+
+        let pooled = clients.get_mut(key)?;
+        pooled.last_used = now;
+        Some(pooled.client.clone())
+    }
+}
@@ -0,0 +1,176 @@
+//! The host's resolv.conf, reloaded when the file changes.
+//!
+//! Tools like NetworkManager or VPN clients rewrite resolv.conf at any time,
+//! so instead of reading it once the file is checked again at most every
+//! check interval and parsed anew once its mtime or size changed. All
+//! servers share one instance which outlives config reloads, readers get
+//! the parsed config as a whole so a reload never mixes old and new values.
+//!
+//! Loopback nameservers such as the 127.0.0.53 stub of systemd-resolved are
+//! not usable from the network namespace of the containers, they are
+//! ignored. If a file lists no other nameserver the next file is used, e.g.
+//! /run/systemd/resolve/resolv.conf which holds the real upstream servers.
+use log::{debug, info, warn};
+use std::fs::{metadata, read};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+pub const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+// Written by systemd-resolved, lists the upstream servers of its stub.
+pub const RESOLV_CONF_FALLBACK_PATH: &str = "/run/systemd/resolve/resolv.conf";
+// How often the files are checked for changes.
+pub const RESOLV_CONF_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct ResolvFile {
+    path: PathBuf,
+    config: Option<resolv_conf::Config>, // last successfully parsed content
+    modified: Option<(SystemTime, u64)>, // mtime and size of the parsed file
+}
+
+impl ResolvFile {
+    // Parse the file again if it changed, returns whether it did. If the
+    // file cannot be read or parsed the last good config is kept.
+    fn refresh(&mut self) -> bool {
+        // stat before reading so a write racing with the read is picked up
+        // by the next check
+        let modified = match metadata(&self.path) {
+            Ok(m) => match m.modified() {
+                Ok(mtime) => (mtime, m.len()),
+                Err(_) => return false,
+            },
+            Err(_) => return false,
+        };
+        if Some(modified) == self.modified {
+            return false;
+        }
+
+        let config = read(&self.path)
+            .ok()
+            .and_then(|buf| resolv_conf::Config::parse(&buf).ok());
+        match config {
+            Some(config) => {
+                debug!(
+                    "Loaded {}, nameservers: {:?}",
+                    self.path.display(),
+                    config.nameservers
+                );
+                self.config = Some(config);
+                self.modified = Some(modified);
+                true
+            }
+            None => {
+                if self.config.is_some() {
+                    warn!(
+                        "Unable to reload {}, keeping previous nameservers",
+                        self.path.display()
+                    );
+                }
+                false
+            }
+        }
+    }
+}
+
+struct ResolvConfState {
+    files: Vec<ResolvFile>,
+    config: Arc<resolv_conf::Config>, // config of the first usable file
+    checked: Instant,                 // last time the files were checked
+}
+
+pub struct ResolvConf {
+    check_interval: Duration,
+    state: Mutex<ResolvConfState>,
+}
+
+impl ResolvConf {
+    // paths are the files to use in order of preference, the first one
+    // listing a non-loopback nameserver wins.
+    pub fn new(paths: Vec<PathBuf>, check_interval: Duration) -> ResolvConf {
+        let mut files: Vec<ResolvFile> = paths
+            .into_iter()
+            .map(|path| ResolvFile {
+                path,
+                config: None,
+                modified: None,
+            })
+            .collect();
+        for file in files.iter_mut() {
+            file.refresh();
+        }
+        let config = Arc::new(usable_config(&files));
+        ResolvConf {
+            check_interval,
+            state: Mutex::new(ResolvConfState {
+                files,
+                config,
+                checked: Instant::now(),
+            }),
+        }
+    }
+
+    // Current config, the files are reloaded first if they changed since
+    // they were parsed. The config has no nameservers if none of the files
+    // lists a usable one.
+    pub fn get(&self) -> Arc<resolv_conf::Config> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if state.checked.elapsed() < self.check_interval {
+            return state.config.clone();
+        }
+        state.checked = Instant::now();
+
+        let mut changed = false;
+        for file in state.files.iter_mut() {
+            changed |= file.refresh();
+        }
+        if changed {
+            state.config = Arc::new(usable_config(&state.files));
+        }
+        state.config.clone()
+    }
+}
+
+// Config of the first file with non-loopback nameservers, loopback ones are
+// removed. Without any the options of the first file are kept.
+fn usable_config(files: &[ResolvFile]) -> resolv_conf::Config {
+    for (i, file) in files.iter().enumerate() {
+        let mut config = match &file.config {
+            Some(config) => config.clone(),
+            None => continue,
+        };
+        config.nameservers.retain(|ns| {
+            let ip: IpAddr = ns.into();
+            !ip.is_loopback()
+        });
+        if config.nameservers.is_empty() {
+            continue;
+        }
+        if i > 0 {
+            info!(
+                "No usable nameserver in {}, using nameservers of {}",
+                files[0].path.display(),
+                file.path.display()
+            );
+        }
+        return config;
+    }
+
+    warn!(
+        "No usable nameserver in any of {:?}, forwarding to the fallback nameserver",
+        paths(files)
+    );
+    let mut config = files
+        .first()
+        .and_then(|file| file.config.clone())
+        .unwrap_or_else(resolv_conf::Config::new);
+    config.nameservers.clear();
+    config
+}
+
+fn paths(files: &[ResolvFile]) -> Vec<&PathBuf> {
+    files.iter().map(|file| &file.path).collect()
+}
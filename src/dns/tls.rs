@@ -0,0 +1,48 @@
+//! TLS settings of encrypted upstream resolvers.
+//!
+//! Certificates of upstream resolvers are verified against the CA
+//! certificates of the host unless a CA bundle is given, which then is the
+//! only source of trusted certificates.
+use log::{debug, warn};
+use rustls::{ClientConfig, RootCertStore};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+// Client config trusting the certificates of ca_file, a PEM bundle, or the
+// CA certificates of the host without ca_file.
+pub fn client_config(ca_file: Option<&Path>) -> io::Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store = match ca_file {
+        Some(path) => load_ca_file(path)?,
+        None => native_roots(),
+    };
+    Ok(config)
+}
+
+fn load_ca_file(path: &Path) -> io::Result<RootCertStore> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut store = RootCertStore::empty();
+    match store.add_pem_file(&mut reader) {
+        Ok((valid, _)) if valid > 0 => {
+            debug!("Loaded {} CA certificates from {}", valid, path.display());
+            Ok(store)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no valid CA certificate in {}", path.display()),
+        )),
+    }
+}
+
+// Failing to load the CA certificates of the host only matters once an
+// encrypted upstream resolver is used, so it is not fatal.
+fn native_roots() -> RootCertStore {
+    match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((partial, e)) => {
+            warn!("Unable to load CA certificates of the host: {}", e);
+            partial.unwrap_or_else(RootCertStore::empty)
+        }
+    }
+}
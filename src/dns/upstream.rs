@@ -0,0 +1,190 @@
+//! State about upstream resolvers shared by all servers.
+//!
+//! Every upstream resolver gets a health record built from the outcome of
+//! the queries forwarded to it: an exponentially weighted moving average of
+//! its latency and the number of failures in a row. A resolver which keeps
+//! failing is backed off, i.e. tried last, for a time growing with every
+//! failure. Failing resolvers go after the healthy ones, which keep their
+//! configured order so that the rotate option of resolv.conf still spreads
+//! the queries among them.
+use crate::dns::cache::ResponseCache;
+use crate::dns::dnssec::Validator;
+use crate::dns::inflight::InFlight;
+use crate::dns::pool::ClientPool;
+use crate::dns::resolv::ResolvConf;
+use log::debug;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Weight of the newest sample in the latency average.
+const LATENCY_EWMA_WEIGHT: f64 = 0.3;
+// Backoff after the first failure, doubled with every further one.
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+// An upstream resolver and the protocol it is queried with.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Nameserver {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Protocol {
+    // plain DNS over udp, truncated answers are retried over tcp
+    Dns,
+    // DNS-over-TLS (RFC 7858), the certificate of the resolver must be
+    // valid for server_name
+    Tls { server_name: String },
+    // DNS-over-HTTPS (RFC 8484) over HTTP/2, queries are posted to
+    // /dns-query and the certificate must be valid for server_name
+    Https { server_name: String },
+}
+
+impl Nameserver {
+    pub fn dns(addr: SocketAddr) -> Nameserver {
+        Nameserver {
+            addr,
+            protocol: Protocol::Dns,
+        }
+    }
+
+    pub fn tls(addr: SocketAddr, server_name: &str) -> Nameserver {
+        Nameserver {
+            addr,
+            protocol: Protocol::Tls {
+                server_name: server_name.to_string(),
+            },
+        }
+    }
+
+    pub fn https(addr: SocketAddr, server_name: &str) -> Nameserver {
+        Nameserver {
+            addr,
+            protocol: Protocol::Https {
+                server_name: server_name.to_string(),
+            },
+        }
+    }
+}
+
+// Same format as in the config files.
+impl fmt::Display for Nameserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.protocol {
+            Protocol::Dns => write!(f, "{}", self.addr),
+            Protocol::Tls { server_name } => write!(f, "tls://{}#{}", self.addr, server_name),
+            Protocol::Https { server_name } => {
+                write!(f, "https://{}#{}", self.addr, server_name)
+            }
+        }
+    }
+}
+
+// Everything about upstream resolvers which is shared by all servers and
+// outlives config reloads.
+#[derive(Clone)]
+pub struct Upstreams {
+    pub resolv_conf: Arc<ResolvConf>,   // host's resolv.conf
+    pub cache: Arc<ResponseCache>,      // answers of upstream resolvers
+    pub health: Arc<UpstreamHealth>,    // health of upstream resolvers
+    pub pool: Arc<ClientPool>,          // clients of upstream resolvers
+    pub inflight: Arc<InFlight>,        // questions being forwarded
+    pub dnssec: Option<Arc<Validator>>, // validator of forwarded answers
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Health {
+    latency: Option<f64>,           // latency average in seconds
+    failures: u32,                  // failures in a row
+    backoff_until: Option<Instant>, // tried last until then
+}
+
+impl Health {
+    fn backed_off(&self, now: Instant) -> bool {
+        self.backoff_until.map_or(false, |until| until > now)
+    }
+}
+
+#[derive(Default)]
+pub struct UpstreamHealth {
+    state: Mutex<HashMap<Nameserver, Health>>,
+}
+
+impl UpstreamHealth {
+    pub fn new() -> UpstreamHealth {
+        UpstreamHealth::default()
+    }
+
+    // Record an answer of nameserver which took latency.
+    pub fn record_success(&self, nameserver: &Nameserver, latency: Duration) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let health = state.entry(nameserver.clone()).or_default();
+        let sample = latency.as_secs_f64();
+        health.latency = Some(match health.latency {
+            Some(avg) => avg + LATENCY_EWMA_WEIGHT * (sample - avg),
+            None => sample,
+        });
+        health.failures = 0;
+        health.backoff_until = None;
+    }
+
+    // Record a failed query to nameserver, i.e. no answer or an answer
+    // which made the server try the next one.
+    pub fn record_failure(&self, nameserver: &Nameserver) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let health = state.entry(nameserver.clone()).or_default();
+        health.failures = health.failures.saturating_add(1);
+        let backoff = BACKOFF_BASE
+            .checked_mul(1 << (health.failures - 1).min(16))
+            .unwrap_or(BACKOFF_MAX)
+            .min(BACKOFF_MAX);
+        health.backoff_until = Some(Instant::now() + backoff);
+        debug!(
+            "Upstream {} failed {} times in a row, backing off for {:?}",
+            nameserver, health.failures, backoff
+        );
+    }
+
+    // nameservers ordered by health: healthy resolvers first in the given
+    // order, then the failing ones by backoff, failures in a row and latency.
+    // Resolvers without any record count as healthy so they get probed.
+    pub fn order(&self, nameservers: &[Nameserver]) -> Vec<Nameserver> {
+        let state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = Instant::now();
+        let mut ordered: Vec<(Nameserver, Health)> = nameservers
+            .iter()
+            .map(|ns| (ns.clone(), state.get(ns).copied().unwrap_or_default()))
+            .collect();
+        drop(state);
+
+        ordered.sort_by(|(_, a), (_, b)| {
+            if a.failures == 0 && b.failures == 0 {
+                return Ordering::Equal;
+            }
+            a.backed_off(now)
+                .cmp(&b.backed_off(now))
+                .then(a.failures.cmp(&b.failures))
+                .then(
+                    a.latency
+                        .unwrap_or(0.0)
+                        .partial_cmp(&b.latency.unwrap_or(0.0))
+                        .unwrap_or(Ordering::Equal),
+                )
+        });
+        ordered.into_iter().map(|(ns, _)| ns).collect()
+    }
+}
@@ -0,0 +1,101 @@
+//! Synthesized authority data (SOA/NS) for zones served by aardvark itself.
+use std::net::IpAddr;
+use trust_dns_client::rr::Name;
+use trust_dns_proto::rr::{rdata::SOA, DNSClass, RData, Record, RecordType};
+
+// SOA timers, only meaningful for secondary servers which aardvark
+// never has, but they must be set to something sane.
+const SOA_REFRESH: i32 = 3600;
+const SOA_RETRY: i32 = 600;
+const SOA_EXPIRE: i32 = 86400;
+
+#[derive(Clone, Debug)]
+pub struct Zone {
+    origin: Name,      // zone apex e.g. dns.podman.
+    hostmaster: Name,  // SOA responsible mailbox
+    serial: u32,       // SOA serial
+    negative_ttl: u32, // SOA minimum, ttl for negative answers
+}
+
+impl Zone {
+    // Create a zone for the given domain, leading and trailing dots are
+    // ignored. Returns None if the domain is empty or not a valid name.
+    pub fn new(domain: &str, serial: u32, negative_ttl: u32) -> Option<Zone> {
+        let domain = domain.trim_matches('.');
+        if domain.is_empty() {
+            return None;
+        }
+
+        let origin = Name::from_str_relaxed(format!("{}.", domain)).ok()?;
+        let hostmaster = Name::from_ascii("hostmaster").ok()?.append_domain(&origin);
+
+        Some(Zone {
+            origin,
+            hostmaster,
+            serial,
+            negative_ttl,
+        })
+    }
+
+    pub fn origin(&self) -> &Name {
+        &self.origin
+    }
+
+    pub fn negative_ttl(&self) -> u32 {
+        self.negative_ttl
+    }
+
+    // Whether name is the zone apex or any name below it.
+    pub fn contains(&self, name: &Name) -> bool {
+        self.origin.zone_of(name)
+    }
+
+    // SOA record of the zone. Its ttl and minimum are both set to the
+    // negative ttl so clients cache NXDOMAIN/NODATA only for that long,
+    // see RFC 2308.
+    pub fn soa_record(&self) -> Record {
+        Record::new()
+            .set_name(self.origin.clone())
+            .set_ttl(self.negative_ttl)
+            .set_rr_type(RecordType::SOA)
+            .set_dns_class(DNSClass::IN)
+            .set_rdata(RData::SOA(SOA::new(
+                self.origin.clone(),
+                self.hostmaster.clone(),
+                self.serial,
+                SOA_REFRESH,
+                SOA_RETRY,
+                SOA_EXPIRE,
+                self.negative_ttl,
+            )))
+            .clone()
+    }
+
+    // NS record of the zone. The apex itself is used as nameserver name,
+    // unlike something like ns.dns.podman it can never shadow a container.
+    pub fn ns_record(&self, ttl: u32) -> Record {
+        Record::new()
+            .set_name(self.origin.clone())
+            .set_ttl(ttl)
+            .set_rr_type(RecordType::NS)
+            .set_dns_class(DNSClass::IN)
+            .set_rdata(RData::NS(self.origin.clone()))
+            .clone()
+    }
+
+    // Address record of the nameserver, i.e. the apex, for the given
+    // server address.
+    pub fn nameserver_record(&self, address: IpAddr, ttl: u32) -> Record {
+        let (rr_type, rdata) = match address {
+            IpAddr::V4(ipv4) => (RecordType::A, RData::A(ipv4)),
+            IpAddr::V6(ipv6) => (RecordType::AAAA, RData::AAAA(ipv6)),
+        };
+        Record::new()
+            .set_name(self.origin.clone())
+            .set_ttl(ttl)
+            .set_rr_type(rr_type)
+            .set_dns_class(DNSClass::IN)
+            .set_rdata(rdata)
+            .clone()
+    }
+}
@@ -24,6 +24,8 @@ struct Opts {
     subcmd: SubCommand,
 }
 
+// parsed once at startup, the size of the options does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum SubCommand {
     /// Runs the aardvark dns server with the specified configuration directory.
@@ -1,20 +1,26 @@
 use crate::backend::DNSBackend;
 use crate::config;
-use crate::config::constants::AARDVARK_PID_FILE;
-use crate::dns::coredns::CoreDns;
-use log::{debug, error, info};
-use signal_hook::consts::signal::SIGHUP;
+use crate::config::constants::{AARDVARK_CACHE_FILE, AARDVARK_PID_FILE};
+use crate::dns::cache::ResponseCache;
+use crate::dns::coredns::{CoreDns, CoreDnsOptions};
+use crate::dns::dnssec::{TrustAnchors, Validator};
+use crate::dns::inflight::InFlight;
+use crate::dns::pool::ClientPool;
+use crate::dns::resolv::{ResolvConf, RESOLV_CONF_CHECK_INTERVAL, RESOLV_CONF_PATH};
+use crate::dns::tls;
+use crate::dns::upstream::{UpstreamHealth, Upstreams};
+use log::{debug, error, info, warn};
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM};
 use signal_hook::iterator::Signals;
 use std::fs;
 use std::net::IpAddr;
-use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use async_broadcast::broadcast;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 
 // Will be only used by server to share backend
@@ -28,6 +34,7 @@ pub fn serve(
     config_path: &str,
     port: u32,
     filter_search_domain: &str,
+    options: CoreDnsOptions,
 ) -> Result<(), std::io::Error> {
     // before serving write its pid to _config_path so other process can notify
     // aardvark of data change.
@@ -54,22 +61,102 @@ pub fn serve(
     // the main loop it will never happen so we have to manually close it
     drop(pid_file);
 
+    // Upstream state does not depend on the config, so it is created once
+    // and kept across reloads. The cache only holds upstream answers and
+    // resolv.conf is reloaded on its own whenever it changes. Clients of
+    // the pool run on a runtime of their own which survives the listeners.
+    let upstreams = Upstreams {
+        resolv_conf: Arc::new(ResolvConf::new(
+            vec![
+                PathBuf::from(RESOLV_CONF_PATH),
+                options.resolv_conf_fallback.clone(),
+            ],
+            RESOLV_CONF_CHECK_INTERVAL,
+        )),
+        cache: Arc::new(ResponseCache::new(
+            options.cache_size,
+            options.cache_min_ttl,
+            options.cache_max_ttl,
+            options.serve_stale,
+            options.prefetch_hits,
+        )),
+        health: Arc::new(UpstreamHealth::new()),
+        pool: Arc::new(ClientPool::new(tls::client_config(
+            options.tls_ca_file.as_deref(),
+        )?)?),
+        inflight: Arc::new(InFlight::new()),
+        dnssec: dnssec_validator(&options)?.map(Arc::new),
+    };
+
+    if options.cache_snapshot {
+        load_cache_snapshot(config_path, &upstreams);
+    }
+
     loop {
-        if let Err(er) = core_serve_loop(config_path, port, filter_search_domain) {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Server Error {}", er),
-            ));
+        match core_serve_loop(
+            config_path,
+            port,
+            filter_search_domain,
+            &options,
+            &upstreams,
+        ) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(er) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Server Error {}", er),
+                ));
+            }
         }
     }
 }
 
+// Validator of forwarded answers if DNSSEC validation is enabled.
+fn dnssec_validator(options: &CoreDnsOptions) -> std::io::Result<Option<Validator>> {
+    if !options.dnssec {
+        return Ok(None);
+    }
+    let anchors = match &options.dnssec_trust_anchor {
+        Some(path) => TrustAnchors::from_file(path)?,
+        None => TrustAnchors::root(),
+    };
+    info!("Validating DNSSEC with {} trust anchors", anchors.len());
+    Ok(Some(Validator::new(anchors)))
+}
+
+// Fill the cache with the answers saved by a previous process.
+fn load_cache_snapshot(config_path: &str, upstreams: &Upstreams) {
+    let path = Path::new(config_path).join(AARDVARK_CACHE_FILE);
+    match upstreams.cache.load(&path) {
+        Ok(count) => info!("Loaded {} cached answers from {}", count, path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("No cache snapshot at {}", path.display())
+        }
+        Err(e) => warn!("Unable to load cache snapshot {}: {}", path.display(), e),
+    }
+}
+
+// Save the cache for the next process, failing to do so is not fatal.
+fn save_cache_snapshot(config_path: &str, upstreams: &Upstreams) {
+    let path = Path::new(config_path).join(AARDVARK_CACHE_FILE);
+    match upstreams.cache.save(&path) {
+        Ok(count) => debug!("Saved {} cached answers to {}", count, path.display()),
+        Err(e) => warn!("Unable to save cache snapshot {}: {}", path.display(), e),
+    }
+}
+
+// Serve the current config until SIGHUP, SIGINT or SIGTERM is received.
+// Returns whether the config should be served again, i.e. false once the
+// server was told to stop and cleaned up after itself.
 fn core_serve_loop(
     config_path: &str,
     port: u32,
     filter_search_domain: &str,
-) -> Result<(), std::io::Error> {
-    let mut signals = Signals::new(&[SIGHUP])?;
+    options: &CoreDnsOptions,
+    upstreams: &Upstreams,
+) -> Result<bool, std::io::Error> {
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM])?;
 
     match config::parse_configs(config_path) {
         Ok((backend, listen_ip_v4, listen_ip_v6)) => {
@@ -85,6 +172,9 @@ fn core_serve_loop(
             if listen_ip_v4.is_empty() && listen_ip_v6.is_empty() {
                 //no configuration found kill the server
                 info!("No configuration found stopping the sever");
+                if options.cache_snapshot {
+                    save_cache_snapshot(config_path, upstreams);
+                }
                 let path = Path::new(config_path).join(AARDVARK_PID_FILE);
                 match fs::remove_file(path) {
                     Ok(_) => {}
@@ -115,6 +205,8 @@ fn core_serve_loop(
                     let backend_arc_clone = shareable_arc.clone();
                     let kill_switch_arc_clone = Arc::clone(&kill_switch);
                     let receiver = rx.clone();
+                    let options_clone = options.clone();
+                    let upstreams_clone = upstreams.clone();
                     let handle = thread::spawn(move || {
                         if let Err(_e) = start_dns_server(
                             &network_name_clone,
@@ -124,6 +216,8 @@ fn core_serve_loop(
                             port,
                             filter_search_domain_clone.to_string(),
                             receiver,
+                            options_clone,
+                            upstreams_clone,
                         ) {
                             error!("Unable to start server {}", _e);
                             return Err(std::io::Error::new(
@@ -146,6 +240,8 @@ fn core_serve_loop(
                     let backend_arc_clone = shareable_arc.clone();
                     let kill_switch_arc_clone = Arc::clone(&kill_switch);
                     let receiver = rx.clone();
+                    let options_clone = options.clone();
+                    let upstreams_clone = upstreams.clone();
                     let handle = thread::spawn(move || {
                         if let Err(_e) = start_dns_server(
                             &network_name_clone,
@@ -155,6 +251,8 @@ fn core_serve_loop(
                             port,
                             filter_search_domain_clone.to_string(),
                             receiver,
+                            options_clone,
+                            upstreams_clone,
                         ) {
                             return Err(std::io::Error::new(
                                 std::io::ErrorKind::Other,
@@ -170,12 +268,18 @@ fn core_serve_loop(
             }
 
             let handle_signal = thread::spawn(move || {
-                if let Some(sig) = signals.forever().next() {
-                    info!("Received SIGHUP will refresh servers: {:?}", sig);
+                let sig = signals.forever().next();
+                match sig {
+                    Some(SIGHUP) => info!("Received SIGHUP will refresh servers: {:?}", SIGHUP),
+                    Some(sig) => info!("Received signal {} stopping the server", sig),
+                    None => {}
                 }
+                sig
             });
 
-            if handle_signal.join().is_ok() {
+            let mut stop = false;
+            if let Ok(sig) = handle_signal.join() {
+                stop = matches!(sig, Some(SIGINT) | Some(SIGTERM));
                 send_broadcast(&tx);
                 if let Ok(mut switch) = kill_switch.lock() {
                     *switch = true;
@@ -192,18 +296,30 @@ fn core_serve_loop(
             tx.close();
             drop(tx);
 
-            Ok(())
-        }
-        Err(e) => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("unable to parse config: {}", e),
-            ))
+            // the listeners are gone so no answer is missing
+            if options.cache_snapshot {
+                save_cache_snapshot(config_path, upstreams);
+            }
+            if stop {
+                fs::remove_file(Path::new(config_path).join(AARDVARK_PID_FILE)).map_err(|err| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("failed to remove the pid file: {}", err),
+                    )
+                })?;
+            }
+
+            Ok(!stop)
         }
+        Err(e) => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("unable to parse config: {}", e),
+        )),
     }
 }
 
 #[tokio::main]
+#[allow(clippy::too_many_arguments)]
 async fn start_dns_server(
     name: &str,
     addr: IpAddr,
@@ -212,8 +328,12 @@ async fn start_dns_server(
     port: u32,
     filter_search_domain: String,
     rx: async_broadcast::Receiver<bool>,
+    options: CoreDnsOptions,
+    upstreams: Upstreams,
 ) -> Result<(), std::io::Error> {
-    let forward: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+    // last resort without any usable nameserver in resolv.conf, ResolvConf
+    // warns whenever the files are loaded without one
+    let forward = options.fallback_nameserver;
     match CoreDns::new(
         addr,
         port,
@@ -224,24 +344,22 @@ async fn start_dns_server(
         kill_switch,
         filter_search_domain,
         rx,
+        options,
+        upstreams,
     )
     .await
     {
-        Ok(mut server) => match server.run().await {
+        Ok(server) => match server.run().await {
             Ok(_) => Ok(()),
-            Err(e) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("unable to start CoreDns server: {}", e),
-                ))
-            }
-        },
-        Err(e) => {
-            return Err(std::io::Error::new(
+            Err(e) => Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
-                format!("unable to create CoreDns server: {}", e),
-            ))
-        }
+                format!("unable to start CoreDns server: {}", e),
+            )),
+        },
+        Err(e) => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("unable to create CoreDns server: {}", e),
+        )),
     }
 }
 
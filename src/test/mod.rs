@@ -1 +1,2 @@
+#[allow(clippy::module_inception, clippy::single_match)]
 pub mod test;
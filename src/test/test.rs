@@ -2,11 +2,38 @@
 
 #[cfg(test)]
 // perform unit tests for config, backend and lookup logic
-// following tests will not test server and event loop since
-// event-loop and server can be tested via integration tests
+// server tests run a server on loopback against stub upstream
+// resolvers, container networks are covered by the bats tests
 mod tests {
-    use aardvark_dns::backend::DNSResult;
+    use aardvark_dns::backend::{DNSResult, Subnet};
     use aardvark_dns::config;
+    use aardvark_dns::dns::cache::ResponseCache;
+    use aardvark_dns::dns::coredns::{CoreDns, CoreDnsOptions};
+    use aardvark_dns::dns::dnssec::{Lookup, Security, TrustAnchors, Validator};
+    use aardvark_dns::dns::inflight::{Flight, InFlight};
+    use aardvark_dns::dns::local::LocalZones;
+    use aardvark_dns::dns::pool::{ClientPool, Transport};
+    use aardvark_dns::dns::resolv::ResolvConf;
+    use aardvark_dns::dns::tls;
+    use aardvark_dns::dns::upstream::{Nameserver, UpstreamHealth, Upstreams};
+    use aardvark_dns::dns::zone::Zone;
+    use futures_util::future::BoxFuture;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{BufReader, Read, Write};
+    use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use trust_dns_client::rr::dnssec::{tbs, KeyFormat, KeyPair, Private};
+    use trust_dns_proto::op::{Edns, Message, MessageType, Query, ResponseCode};
+    use trust_dns_proto::rr::dnssec::rdata::{
+        DNSSECRData, DNSSECRecordType, DNSKEY, NSEC, NSEC3, SIG,
+    };
+    use trust_dns_proto::rr::dnssec::{Algorithm, DigestType, Nsec3HashAlgorithm};
+    use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+    use trust_dns_proto::rr::{DNSClass, Name, RData, Record, RecordType};
     /* -------------------------------------------- */
     // --------- Test aardvark-dns config ---------
     /* -------------------------------------------- */
@@ -38,6 +65,185 @@ mod tests {
             Err(_) => {}
         }
     }
+    #[test]
+    // Parse config files with ttl options, container ttl
+    // must win over network ttl.
+    fn test_parsing_config_files_with_ttl() {
+        match config::parse_configs("src/test/config/podman_ttl") {
+            Ok((backend, _, _)) => {
+                assert_eq!(
+                    backend.network_ttl("podman_ttl", &"10.90.0.2".parse().unwrap()),
+                    Some(30)
+                );
+                assert_eq!(
+                    backend
+                        .lookup_ttl(&"10.90.0.2".parse().unwrap(), &"10.90.0.3".parse().unwrap()),
+                    Some(5)
+                );
+                // unknown address has no configured ttl
+                assert_eq!(
+                    backend.network_ttl("podman_ttl", &"10.90.0.9".parse().unwrap()),
+                    None
+                );
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[test]
+    // Parse dns servers of a network, port defaults to 53, 853 for
+    // tls and 443 for https
+    fn test_parsing_config_files_with_dns_servers() {
+        match config::parse_configs("src/test/config/podman_dns") {
+            Ok((backend, _, _)) => {
+                let mut expected =
+                    nameservers(&["10.0.0.53:53", "10.0.0.54:5353", "[fd00::53]:5353"]);
+                expected.push(Nameserver::tls(
+                    "10.0.0.98:853".parse().unwrap(),
+                    "dns.example",
+                ));
+                expected.push(Nameserver::tls(
+                    "[fd00::98]:8853".parse().unwrap(),
+                    "dns.example",
+                ));
+                expected.push(Nameserver::https(
+                    "10.0.0.97:443".parse().unwrap(),
+                    "dns.example",
+                ));
+                expected.push(Nameserver::https(
+                    "[fd00::97]:8443".parse().unwrap(),
+                    "dns.example",
+                ));
+                assert_eq!(backend.network_nameservers("podman_dns"), Some(&expected));
+                assert_eq!(backend.network_nameservers("podman"), None);
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[test]
+    // Parse dns servers of a container, they apply to all of
+    // its addresses.
+    fn test_parsing_config_files_with_container_dns_servers() {
+        match config::parse_configs("src/test/config/podman_dns") {
+            Ok((backend, _, _)) => {
+                let expected = nameservers(&["10.0.0.99:53", "[fd00::99]:5353"]);
+                assert_eq!(
+                    backend.ctr_nameservers(&"10.91.0.3".parse().unwrap()),
+                    Some(&expected)
+                );
+                assert_eq!(
+                    backend.ctr_nameservers(&"fd91::3".parse().unwrap()),
+                    Some(&expected)
+                );
+                assert_eq!(backend.ctr_nameservers(&"10.91.0.2".parse().unwrap()), None);
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[test]
+    // Parse forwarding rules, the longest matching domain wins and
+    // rules of a container win over the rules of the network.
+    fn test_parsing_config_files_with_forward_rules() {
+        match config::parse_configs("src/test/config/podman_dns") {
+            Ok((backend, _, _)) => {
+                let network = "podman_dns";
+                let ctr: IpAddr = "10.91.0.2".parse().unwrap();
+                let corp = nameservers(&["10.0.1.1:53"]);
+                let eng = nameservers(&["10.0.1.2:5353"]);
+                assert_eq!(
+                    backend.forward_nameservers(network, &ctr, "corp.example."),
+                    Some(&corp)
+                );
+                assert_eq!(
+                    backend.forward_nameservers(network, &ctr, "www.CORP.example."),
+                    Some(&corp)
+                );
+                assert_eq!(
+                    backend.forward_nameservers(network, &ctr, "git.eng.corp.example."),
+                    Some(&eng)
+                );
+                assert_eq!(
+                    backend.forward_nameservers(network, &ctr, "notcorp.example."),
+                    None
+                );
+                assert_eq!(backend.forward_nameservers(network, &ctr, "example."), None);
+                assert_eq!(
+                    backend.forward_nameservers("podman", &ctr, "corp.example."),
+                    None
+                );
+
+                let ctr: IpAddr = "fd91::3".parse().unwrap();
+                let expected = nameservers(&["10.0.1.99:53"]);
+                assert_eq!(
+                    backend.forward_nameservers(network, &ctr, "git.eng.corp.example."),
+                    Some(&expected)
+                );
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[test]
+    // Addresses in the subnets of the requester's networks or of the
+    // server's network are in the reverse zone of the subnet.
+    fn test_parsing_config_files_with_subnets() {
+        match config::parse_configs("src/test/config/podman_dns") {
+            Ok((backend, _, _)) => {
+                let network = "podman_dns";
+                let ctr: IpAddr = "10.91.0.2".parse().unwrap();
+                let unknown: IpAddr = "10.91.15.9".parse().unwrap();
+                assert_eq!(
+                    backend.reverse_zone(network, &ctr, &unknown),
+                    Some("15.91.10.in-addr.arpa".to_string())
+                );
+                let unknown: IpAddr = "fd91::1:9".parse().unwrap();
+                assert_eq!(
+                    backend.reverse_zone("podman", &ctr, &unknown),
+                    Some("0.0.0.0.0.0.0.0.0.0.0.0.1.9.d.f.ip6.arpa".to_string())
+                );
+                let outside: IpAddr = "10.91.16.1".parse().unwrap();
+                assert_eq!(backend.reverse_zone(network, &ctr, &outside), None);
+                let other: IpAddr = "10.88.0.2".parse().unwrap();
+                assert_eq!(backend.reverse_zone("podman", &other, &unknown), None);
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[test]
+    fn test_subnet_reverse_zone() {
+        let subnet = Subnet::new("10.89.0.0".parse().unwrap(), 16).unwrap();
+        assert_eq!(
+            subnet.reverse_zone(&"10.89.3.4".parse().unwrap()),
+            Some("89.10.in-addr.arpa".to_string())
+        );
+        assert_eq!(subnet.reverse_zone(&"10.90.3.4".parse().unwrap()), None);
+        assert_eq!(subnet.reverse_zone(&"fd00::1".parse().unwrap()), None);
+        let subnet = Subnet::new("10.89.0.0".parse().unwrap(), 32).unwrap();
+        assert_eq!(
+            subnet.reverse_zone(&"10.89.0.0".parse().unwrap()),
+            Some("0.0.89.10.in-addr.arpa".to_string())
+        );
+        let subnet = Subnet::new("fd00:1:2::".parse().unwrap(), 62).unwrap();
+        assert_eq!(
+            subnet.reverse_zone(&"fd00:1:2:3::1".parse().unwrap()),
+            Some("3.0.0.0.2.0.0.0.1.0.0.0.0.0.d.f.ip6.arpa".to_string())
+        );
+        assert!(Subnet::new("10.89.0.0".parse().unwrap(), 0).is_none());
+        assert!(Subnet::new("10.89.0.0".parse().unwrap(), 33).is_none());
+        assert!(Subnet::new("fd00::".parse().unwrap(), 129).is_none());
+    }
+    #[test]
+    // Config files without ttl options must not set any ttl
+    fn test_parsing_config_files_without_ttl() {
+        match config::parse_configs("src/test/config/podman") {
+            Ok((backend, _, _)) => {
+                assert_eq!(
+                    backend
+                        .lookup_ttl(&"10.88.0.2".parse().unwrap(), &"10.88.0.4".parse().unwrap()),
+                    None
+                );
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
     /* -------------------------------------------- */
     // -------Test aardvark-dns lookup logic ------
     /* -------------------------------------------- */
@@ -50,7 +256,11 @@ mod tests {
     fn test_lookup_queries_from_backend_simulate_same_container_request_from_v4_on_v4_entries() {
         match config::parse_configs("src/test/config/podman") {
             Ok((backend, _, _)) => {
-                match backend.lookup(&"10.88.0.2".parse().unwrap(), "condescendingnash") {
+                match backend.lookup(
+                    &"10.88.0.2".parse().unwrap(),
+                    "condescendingnash",
+                    RecordType::ANY,
+                ) {
                     DNSResult::Success(ip_vec) => {
                         assert_eq!(ip_vec.len(), 1);
                         assert_eq!("10.88.0.2".parse(), Ok(ip_vec[0]));
@@ -71,7 +281,7 @@ mod tests {
     ) {
         match config::parse_configs("src/test/config/podman") {
             Ok((backend, _, _)) => {
-                match backend.lookup(&"10.88.0.2".parse().unwrap(), "helloworld") {
+                match backend.lookup(&"10.88.0.2".parse().unwrap(), "helloworld", RecordType::ANY) {
                     DNSResult::Success(ip_vec) => {
                         assert_eq!(ip_vec.len(), 1);
                         assert_eq!("10.88.0.5".parse(), Ok(ip_vec[0]));
@@ -92,7 +302,7 @@ mod tests {
     ) {
         match config::parse_configs("src/test/config/podman") {
             Ok((backend, _, _)) => {
-                match backend.lookup(&"10.88.0.2".parse().unwrap(), "HELLOWORLD") {
+                match backend.lookup(&"10.88.0.2".parse().unwrap(), "HELLOWORLD", RecordType::ANY) {
                     DNSResult::Success(ip_vec) => {
                         assert_eq!(ip_vec.len(), 1);
                         assert_eq!("10.88.0.5".parse(), Ok(ip_vec[0]));
@@ -109,7 +319,11 @@ mod tests {
     fn test_lookup_queries_from_backend_simulate_nx_domain() {
         match config::parse_configs("src/test/config/podman") {
             Ok((backend, _, _)) => {
-                match backend.lookup(&"10.88.0.2".parse().unwrap(), "somebadquery") {
+                match backend.lookup(
+                    &"10.88.0.2".parse().unwrap(),
+                    "somebadquery",
+                    RecordType::ANY,
+                ) {
                     DNSResult::NXDomain => {}
                     _ => panic!("unexpected dns result"),
                 }
@@ -126,7 +340,11 @@ mod tests {
     fn test_lookup_queries_from_backend_simulate_different_container_request_from_v4() {
         match config::parse_configs("src/test/config/podman") {
             Ok((backend, _, _)) => {
-                match backend.lookup(&"10.88.0.2".parse().unwrap(), "trustingzhukovsky") {
+                match backend.lookup(
+                    &"10.88.0.2".parse().unwrap(),
+                    "trustingzhukovsky",
+                    RecordType::ANY,
+                ) {
                     DNSResult::Success(ip_vec) => {
                         assert_eq!(ip_vec.len(), 1);
                         assert_eq!("10.88.0.4".parse(), Ok(ip_vec[0]));
@@ -145,14 +363,16 @@ mod tests {
     // Same container --> (resolve) different container name by alias --> (on) Same Network
     fn test_lookup_queries_from_backend_simulate_different_container_request_from_v4_by_alias() {
         match config::parse_configs("src/test/config/podman") {
-            Ok((backend, _, _)) => match backend.lookup(&"10.88.0.2".parse().unwrap(), "ctr1") {
-                DNSResult::Success(ip_vec) => {
-                    // verfiy length for issues like: https://github.com/containers/aardvark-dns/issues/120
-                    assert_eq!(ip_vec.len(), 1);
-                    assert_eq!("10.88.0.4".parse(), Ok(ip_vec[0]));
+            Ok((backend, _, _)) => {
+                match backend.lookup(&"10.88.0.2".parse().unwrap(), "ctr1", RecordType::ANY) {
+                    DNSResult::Success(ip_vec) => {
+                        // verfiy length for issues like: https://github.com/containers/aardvark-dns/issues/120
+                        assert_eq!(ip_vec.len(), 1);
+                        assert_eq!("10.88.0.4".parse(), Ok(ip_vec[0]));
+                    }
+                    _ => panic!("unexpected dns result"),
                 }
-                _ => panic!("unexpected dns result"),
-            },
+            }
             Err(e) => panic!("{}", e),
         }
     }
@@ -168,7 +388,7 @@ mod tests {
             Ok((backend, listen_ip_v4, listen_ip_v6)) => {
                 listen_ip_v6.contains_key("podman_v6_entries");
                 listen_ip_v4.contains_key("podman_v6_entries");
-                match backend.lookup(&"10.89.0.2".parse().unwrap(), "test1") {
+                match backend.lookup(&"10.89.0.2".parse().unwrap(), "test1", RecordType::ANY) {
                     DNSResult::Success(ip_vec) => {
                         // verfiy length for issues like: https://github.com/containers/aardvark-dns/issues/120
                         assert_eq!(ip_vec.len(), 2);
@@ -193,7 +413,11 @@ mod tests {
             Ok((backend, listen_ip_v4, listen_ip_v6)) => {
                 listen_ip_v6.contains_key("podman_v6_entries");
                 listen_ip_v4.contains_key("podman_v6_entries");
-                match backend.lookup(&"fdfd:733b:dc3:220b::2".parse().unwrap(), "test1") {
+                match backend.lookup(
+                    &"fdfd:733b:dc3:220b::2".parse().unwrap(),
+                    "test1",
+                    RecordType::ANY,
+                ) {
                     DNSResult::Success(ip_vec) => {
                         // verfiy length for issues like: https://github.com/containers/aardvark-dns/issues/120
                         assert_eq!(ip_vec.len(), 2);
@@ -218,7 +442,11 @@ mod tests {
             Ok((backend, listen_ip_v4, listen_ip_v6)) => {
                 listen_ip_v6.contains_key("podman_v6_entries");
                 listen_ip_v4.contains_key("podman_v6_entries");
-                match backend.lookup(&"fdfd:733b:dc3:220b::2".parse().unwrap(), "test2") {
+                match backend.lookup(
+                    &"fdfd:733b:dc3:220b::2".parse().unwrap(),
+                    "test2",
+                    RecordType::ANY,
+                ) {
                     DNSResult::Success(ip_vec) => {
                         // verfiy length for issues like: https://github.com/containers/aardvark-dns/issues/120
                         assert_eq!(ip_vec.len(), 2);
@@ -243,7 +471,7 @@ mod tests {
             Ok((backend, listen_ip_v4, listen_ip_v6)) => {
                 listen_ip_v6.contains_key("podman_v6_entries");
                 listen_ip_v4.contains_key("podman_v6_entries");
-                match backend.lookup(&"10.89.0.2".parse().unwrap(), "test2") {
+                match backend.lookup(&"10.89.0.2".parse().unwrap(), "test2", RecordType::ANY) {
                     DNSResult::Success(ip_vec) => {
                         // verfiy length for issues like: https://github.com/containers/aardvark-dns/issues/120
                         assert_eq!(ip_vec.len(), 2);
@@ -268,7 +496,11 @@ mod tests {
             Ok((backend, listen_ip_v4, listen_ip_v6)) => {
                 listen_ip_v6.contains_key("podman_v6_entries");
                 listen_ip_v4.contains_key("podman_v6_entries");
-                match backend.lookup(&"10.89.0.2".parse().unwrap(), "88dde8a24897") {
+                match backend.lookup(
+                    &"10.89.0.2".parse().unwrap(),
+                    "88dde8a24897",
+                    RecordType::ANY,
+                ) {
                     DNSResult::Success(ip_vec) => {
                         // verfiy length for issues like: https://github.com/containers/aardvark-dns/issues/120
                         assert_eq!(ip_vec.len(), 2);
@@ -281,6 +513,59 @@ mod tests {
             Err(e) => panic!("{}", e),
         }
     }
+    #[test]
+    // Check lookup query from backend and simulate
+    // A and AAAA requests for a container with v4 and v6 entries,
+    // aardvark must only return addresses of the requested type.
+    // Same container --> (resolve) different container name --> (on) Same Network
+    fn test_lookup_queries_from_backend_simulate_record_type_filter_on_v6_and_v4_entries() {
+        match config::parse_configs("src/test/config/podman_v6_entries") {
+            Ok((backend, _, _)) => {
+                match backend.lookup(&"10.89.0.2".parse().unwrap(), "test2", RecordType::A) {
+                    DNSResult::Success(ip_vec) => {
+                        assert_eq!(ip_vec.len(), 1);
+                        assert_eq!("10.89.0.3".parse(), Ok(ip_vec[0]));
+                    }
+                    _ => panic!("unexpected dns result"),
+                }
+                match backend.lookup(&"10.89.0.2".parse().unwrap(), "test2", RecordType::AAAA) {
+                    DNSResult::Success(ip_vec) => {
+                        assert_eq!(ip_vec.len(), 1);
+                        assert_eq!("fdfd:733b:dc3:220b::3".parse(), Ok(ip_vec[0]));
+                    }
+                    _ => panic!("unexpected dns result"),
+                }
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[test]
+    // Check lookup query from backend and simulate
+    // AAAA and MX requests for a container with only a v4 entry,
+    // name exists so aardvark must return NODATA instead of NXDOMAIN.
+    fn test_lookup_queries_from_backend_simulate_no_data() {
+        match config::parse_configs("src/test/config/podman") {
+            Ok((backend, _, _)) => {
+                match backend.lookup(&"10.88.0.2".parse().unwrap(), "ctr1", RecordType::AAAA) {
+                    DNSResult::NoData => {}
+                    _ => panic!("unexpected dns result"),
+                }
+                match backend.lookup(&"10.88.0.2".parse().unwrap(), "ctr1", RecordType::MX) {
+                    DNSResult::NoData => {}
+                    _ => panic!("unexpected dns result"),
+                }
+                match backend.lookup(
+                    &"10.88.0.2".parse().unwrap(),
+                    "somebadquery",
+                    RecordType::AAAA,
+                ) {
+                    DNSResult::NXDomain => {}
+                    _ => panic!("unexpected dns result"),
+                }
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
     /* -------------------------------------------- */
     // ---Test aardvark-dns reverse lookup logic --
     /* -------------------------------------------- */
@@ -437,4 +722,2012 @@ mod tests {
             Err(e) => panic!("{}", e),
         }
     }
+    /* -------------------------------------------- */
+    // --------- Test aardvark-dns zone ---------
+    /* -------------------------------------------- */
+    #[test]
+    // Zone built from the default search domain must contain
+    // names below it but not unrelated names.
+    fn test_zone_contains_names_in_search_domain() {
+        let zone = Zone::new(".dns.podman", 1, 10).unwrap();
+        assert_eq!(zone.origin(), &Name::from_ascii("dns.podman.").unwrap());
+        assert!(zone.contains(&Name::from_ascii("dns.podman.").unwrap()));
+        assert!(zone.contains(&Name::from_ascii("AOne.DNS.podman.").unwrap()));
+        assert!(!zone.contains(&Name::from_ascii("aone.").unwrap()));
+        assert!(!zone.contains(&Name::from_ascii("podman.io.").unwrap()));
+        assert!(Zone::new("", 1, 10).is_none());
+        assert!(Zone::new(".", 1, 10).is_none());
+    }
+    #[test]
+    // SOA ttl and minimum must both be the negative ttl
+    fn test_zone_soa_uses_negative_ttl() {
+        let zone = Zone::new("dns.podman", 42, 7).unwrap();
+        let soa = zone.soa_record();
+        assert_eq!(soa.ttl(), 7);
+        match soa.rdata() {
+            RData::SOA(soa) => {
+                assert_eq!(soa.serial(), 42);
+                assert_eq!(soa.minimum(), 7);
+            }
+            _ => panic!("unexpected record data"),
+        }
+    }
+    fn local_answer(zones: &LocalZones, name: &str, record_type: RecordType) -> Option<Message> {
+        let name = Name::from_ascii(name).unwrap();
+        let mut req = Message::new();
+        req.add_query(Query::query(name.clone(), record_type));
+        zones.answer(&name, record_type, &req, 60, 10, false)
+    }
+    #[test]
+    // localhost resolves to loopback, other special-use names do not exist
+    fn test_builtin_local_zones() {
+        let zones = LocalZones::builtin();
+        let resp = local_answer(&zones, "localhost.", RecordType::A).unwrap();
+        assert!(resp.authoritative());
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::A("127.0.0.1".parse().unwrap())
+        );
+        let resp = local_answer(&zones, "db.LocalHost.", RecordType::AAAA).unwrap();
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::AAAA("::1".parse().unwrap())
+        );
+        assert_eq!(resp.answers()[0].ttl(), 60);
+        // names of loopback zones exist but only have addresses
+        let resp = local_answer(&zones, "localhost.", RecordType::MX).unwrap();
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(resp.answers().is_empty());
+        assert_eq!(resp.name_servers()[0].record_type(), RecordType::SOA);
+        for name in &[
+            "host.invalid.",
+            "printer.local.",
+            "app.test.",
+            "1.0.168.192.in-addr.arpa.",
+        ] {
+            let resp = local_answer(&zones, name, RecordType::A).unwrap();
+            assert_eq!(resp.response_code(), ResponseCode::NXDomain, "{}", name);
+            assert_eq!(resp.name_servers()[0].ttl(), 10);
+        }
+        let ip6_loopback = format!("1.{}.ip6.arpa.", ["0"; 31].join("."));
+        let resp = local_answer(&zones, &ip6_loopback, RecordType::PTR).unwrap();
+        assert_eq!(resp.name_servers()[0].name().to_string(), ip6_loopback);
+        // the apex of empty zones exists with its SOA
+        let resp = local_answer(&zones, "168.192.in-addr.arpa.", RecordType::SOA).unwrap();
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert_eq!(resp.answers()[0].record_type(), RecordType::SOA);
+        assert!(local_answer(&zones, "example.com.", RecordType::A).is_none());
+        assert!(local_answer(&zones, "1.0.0.8.in-addr.arpa.", RecordType::PTR).is_none());
+        assert!(local_answer(&zones, "1.0.32.172.in-addr.arpa.", RecordType::PTR).is_none());
+
+        // private reverse zones are left to the requester's own upstreams
+        let name = Name::from_ascii("1.0.168.192.in-addr.arpa.").unwrap();
+        let mut req = Message::new();
+        req.add_query(Query::query(name.clone(), RecordType::PTR));
+        assert!(zones
+            .answer(&name, RecordType::PTR, &req, 60, 10, true)
+            .is_none());
+        let name = Name::from_ascii("1.0.0.127.in-addr.arpa.").unwrap();
+        assert!(zones
+            .answer(&name, RecordType::PTR, &req, 60, 10, true)
+            .is_some());
+    }
+    #[test]
+    fn test_parsing_local_zones() {
+        let zones =
+            LocalZones::parse("corp.example=loopback, home.arpa, sub.home.arpa=loopback").unwrap();
+        assert_eq!(zones.len(), 3);
+        let resp = local_answer(&zones, "db.corp.example.", RecordType::A).unwrap();
+        assert_eq!(resp.answers().len(), 1);
+        assert_eq!(
+            local_answer(&zones, "nas.home.arpa.", RecordType::A)
+                .unwrap()
+                .response_code(),
+            ResponseCode::NXDomain
+        );
+        // the most specific zone wins
+        let resp = local_answer(&zones, "nas.sub.home.arpa.", RecordType::A).unwrap();
+        assert_eq!(resp.answers().len(), 1);
+        assert!(local_answer(&zones, "localhost.", RecordType::A).is_none());
+        assert!(LocalZones::parse("").unwrap().is_empty());
+        assert!(LocalZones::parse("home.arpa=bogus").is_err());
+        assert!(LocalZones::parse("bad..zone").is_err());
+    }
+    /* -------------------------------------------- */
+    // --------- Test aardvark-dns cache ---------
+    /* -------------------------------------------- */
+    fn nameservers(addrs: &[&str]) -> Vec<Nameserver> {
+        addrs
+            .iter()
+            .map(|addr| Nameserver::dns(addr.parse().unwrap()))
+            .collect()
+    }
+    fn upstreams() -> Vec<Nameserver> {
+        nameservers(&["10.0.0.53:53"])
+    }
+    fn cache_query(id: u16, name: &str) -> Message {
+        let mut req = Message::new();
+        req.set_id(id)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+        req
+    }
+    fn cache_answer(req: &Message, ttl: u32) -> Message {
+        let mut resp = req.clone();
+        resp.set_message_type(MessageType::Response)
+            .add_answer(Record::from_rdata(
+                req.queries()[0].name().clone(),
+                ttl,
+                RData::A("10.0.0.1".parse().unwrap()),
+            ));
+        resp
+    }
+    #[test]
+    // Cached answers must carry the id of the new query and
+    // a ttl clamped to the configured bounds.
+    fn test_cache_positive_answer() {
+        let cache = ResponseCache::new(10, 30, 100, 0, 0);
+        let req = cache_query(1, "example.com.");
+        assert!(cache.get(&req, &upstreams()).is_none());
+        cache.insert(&req, &upstreams(), &cache_answer(&req, 5));
+
+        let resp = cache
+            .get(&cache_query(2, "EXAMPLE.com."), &upstreams())
+            .unwrap();
+        assert_eq!(resp.id(), 2);
+        assert_eq!(resp.answers().len(), 1);
+        assert_eq!(resp.answers()[0].ttl(), 30);
+
+        let req = cache_query(3, "example.org.");
+        cache.insert(&req, &upstreams(), &cache_answer(&req, 1000));
+        assert_eq!(
+            cache.get(&req, &upstreams()).unwrap().answers()[0].ttl(),
+            100
+        );
+    }
+    #[test]
+    // Negative answers are cached only with a SOA, other
+    // errors are never cached.
+    fn test_cache_negative_answers() {
+        let cache = ResponseCache::new(10, 0, 86400, 0, 0);
+        let zone = Zone::new("example.com", 1, 20).unwrap();
+
+        let req = cache_query(1, "missing.example.com.");
+        let mut resp = req.clone();
+        resp.set_message_type(MessageType::Response)
+            .set_response_code(ResponseCode::NXDomain);
+        cache.insert(&req, &upstreams(), &resp);
+        assert!(cache.get(&req, &upstreams()).is_none());
+
+        resp.add_name_server(zone.soa_record());
+        cache.insert(&req, &upstreams(), &resp);
+        let cached = cache.get(&req, &upstreams()).unwrap();
+        assert_eq!(cached.response_code(), ResponseCode::NXDomain);
+        assert_eq!(cached.name_servers()[0].ttl(), 20);
+
+        let req = cache_query(2, "broken.example.com.");
+        let mut resp = req.clone();
+        resp.set_message_type(MessageType::Response)
+            .set_response_code(ResponseCode::ServFail)
+            .add_name_server(zone.soa_record());
+        cache.insert(&req, &upstreams(), &resp);
+        assert!(cache.get(&req, &upstreams()).is_none());
+    }
+    #[test]
+    // Answers of one set of upstream resolvers must not be
+    // returned for queries sent to other resolvers.
+    fn test_cache_separates_upstreams() {
+        let cache = ResponseCache::new(10, 0, 86400, 0, 0);
+        let req = cache_query(1, "example.com.");
+        cache.insert(&req, &upstreams(), &cache_answer(&req, 60));
+        let other = nameservers(&["10.0.0.53:5353"]);
+        assert!(cache.get(&req, &other).is_none());
+        let tls = vec![Nameserver::tls(
+            "10.0.0.53:853".parse().unwrap(),
+            "dns.example",
+        )];
+        assert!(cache.get(&req, &tls).is_none());
+        assert!(cache.get(&req, &upstreams()).is_some());
+    }
+    #[test]
+    // Least recently used answer is evicted once the cache is full
+    fn test_cache_lru_eviction() {
+        let cache = ResponseCache::new(2, 0, 86400, 0, 0);
+        let first = cache_query(1, "first.com.");
+        let second = cache_query(2, "second.com.");
+        let third = cache_query(3, "third.com.");
+        cache.insert(&first, &upstreams(), &cache_answer(&first, 60));
+        cache.insert(&second, &upstreams(), &cache_answer(&second, 60));
+        // mark first as recently used so second gets evicted
+        assert!(cache.get(&first, &upstreams()).is_some());
+        cache.insert(&third, &upstreams(), &cache_answer(&third, 60));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&first, &upstreams()).is_some());
+        assert!(cache.get(&second, &upstreams()).is_none());
+        assert!(cache.get(&third, &upstreams()).is_some());
+    }
+    #[test]
+    // Cache of size 0 must never store anything
+    fn test_cache_disabled() {
+        let cache = ResponseCache::new(0, 0, 86400, 0, 0);
+        let req = cache_query(1, "example.com.");
+        cache.insert(&req, &upstreams(), &cache_answer(&req, 60));
+        assert!(cache.is_empty());
+        assert!(cache.get(&req, &upstreams()).is_none());
+    }
+    #[test]
+    // Expired answers are only returned as stale answers with
+    // a short ttl and only within the stale window.
+    fn test_cache_stale_answers() {
+        let req = cache_query(1, "example.com.");
+        let cache = ResponseCache::new(10, 0, 86400, 60, 0);
+        let fresh = ResponseCache::new(10, 0, 86400, 0, 0);
+        for cache in [&cache, &fresh] {
+            cache.insert(&req, &upstreams(), &cache_answer(&req, 1));
+            assert!(cache.get_stale(&req, &upstreams()).is_none());
+        }
+        std::thread::sleep(Duration::from_millis(1100));
+
+        assert!(cache.get(&req, &upstreams()).is_none());
+        let stale = cache
+            .get_stale(&cache_query(2, "example.com."), &upstreams())
+            .unwrap();
+        assert_eq!(stale.id(), 2);
+        assert_eq!(stale.answers()[0].ttl(), 30);
+        assert_eq!(cache.len(), 1);
+
+        assert!(fresh.get_stale(&req, &upstreams()).is_none());
+        assert!(fresh.is_empty());
+    }
+    #[test]
+    // Answers are due for a refresh once, after enough hits and
+    // shortly before they expire.
+    fn test_cache_prefetch_due() {
+        let cache = ResponseCache::new(10, 0, 86400, 0, 2);
+        let expiring = cache_query(1, "expiring.com.");
+        let lasting = cache_query(2, "lasting.com.");
+        cache.insert(&expiring, &upstreams(), &cache_answer(&expiring, 1));
+        cache.insert(&lasting, &upstreams(), &cache_answer(&lasting, 100));
+        for req in [&expiring, &lasting] {
+            assert!(cache.get(req, &upstreams()).is_some());
+            assert!(!cache.prefetch_due(req, &upstreams()));
+            assert!(cache.get(req, &upstreams()).is_some());
+        }
+        assert!(cache.prefetch_due(&expiring, &upstreams()));
+        assert!(!cache.prefetch_due(&expiring, &upstreams()));
+        assert!(!cache.prefetch_due(&lasting, &upstreams()));
+
+        // a failed refresh leaves the answer due
+        cache.prefetch_done(&expiring, &upstreams());
+        assert!(cache.prefetch_due(&expiring, &upstreams()));
+
+        // the refreshed answer starts over
+        cache.insert(&expiring, &upstreams(), &cache_answer(&expiring, 1));
+        assert!(!cache.prefetch_due(&expiring, &upstreams()));
+    }
+    #[test]
+    // Saved answers are loaded for the same key with the time since
+    // they were received taken off their ttl.
+    fn test_cache_snapshot() {
+        let path = std::env::temp_dir().join(format!("aardvark-cache-{}", std::process::id()));
+        let tls = vec![Nameserver::tls(
+            "10.0.0.53:853".parse().unwrap(),
+            "dns.example",
+        )];
+        let req = cache_query(1, "example.com.");
+        let cache = ResponseCache::new(10, 0, 86400, 0, 0);
+        cache.insert(&req, &upstreams(), &cache_answer(&req, 60));
+        cache.insert(&req, &tls, &cache_answer(&req, 120));
+        cache.insert(&req, &[], &cache_answer(&req, 180));
+        assert_eq!(cache.save(&path).unwrap(), 3);
+
+        let loaded = ResponseCache::new(10, 0, 86400, 0, 0);
+        assert_eq!(loaded.load(&path).unwrap(), 3);
+        let resp = loaded.get(&req, &[]).unwrap();
+        assert_eq!(resp.answers()[0].ttl(), 180);
+        let resp = loaded.get(&cache_query(2, "Example.com."), &tls).unwrap();
+        assert_eq!(resp.id(), 2);
+        assert_eq!(resp.answers()[0].ttl(), 120);
+        assert!(loaded.get(&req, &upstreams()).is_some());
+
+        // answers received 100 seconds ago
+        let received = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 100;
+        let wire = data_encoding::BASE64.encode(&cache_answer(&req, 300).to_vec().unwrap());
+        std::fs::write(
+            &path,
+            format!(
+                "{received} 300 0 0 10.0.0.53:53 {wire}\n{received} 60 0 0 10.0.0.1:53 {wire}\nbroken\n",
+                received = received,
+                wire = wire
+            ),
+        )
+        .unwrap();
+        let loaded = ResponseCache::new(10, 0, 86400, 0, 0);
+        assert_eq!(loaded.load(&path).unwrap(), 1);
+        let ttl = loaded.get(&req, &upstreams()).unwrap().answers()[0].ttl();
+        assert!((199..=200).contains(&ttl));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.load(&path).is_err());
+    }
+    /* -------------------------------------------- */
+    // --------- Test aardvark-dns resolv.conf ---------
+    /* -------------------------------------------- */
+    #[test]
+    // Changes of resolv.conf must be picked up without creating
+    // a new instance, a removed file keeps the last nameservers.
+    fn test_resolv_conf_reload() {
+        let path =
+            std::env::temp_dir().join(format!("aardvark-resolv-{}.conf", std::process::id()));
+        std::fs::write(&path, "nameserver 10.0.0.1\n").unwrap();
+        let resolv_conf = ResolvConf::new(vec![path.clone()], Duration::ZERO);
+        assert_eq!(
+            resolv_conf.get().nameservers[0].to_string(),
+            "10.0.0.1".to_string()
+        );
+
+        std::fs::write(&path, "nameserver 10.0.0.2\nnameserver 10.0.0.3\n").unwrap();
+        let config = resolv_conf.get();
+        assert_eq!(config.nameservers.len(), 2);
+        assert_eq!(config.nameservers[0].to_string(), "10.0.0.2".to_string());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resolv_conf.get().nameservers.len(), 2);
+    }
+    #[test]
+    // Loopback nameservers must be skipped in favour of the
+    // next file, without any usable one no nameserver is left.
+    fn test_resolv_conf_loopback_fallback() {
+        let dir = std::env::temp_dir();
+        let stub = dir.join(format!("aardvark-stub-{}.conf", std::process::id()));
+        let upstream = dir.join(format!("aardvark-upstream-{}.conf", std::process::id()));
+        std::fs::write(&stub, "nameserver 127.0.0.53\noptions edns0\n").unwrap();
+        std::fs::write(&upstream, "nameserver 10.0.0.9\n").unwrap();
+
+        let resolv_conf = ResolvConf::new(vec![stub.clone(), upstream.clone()], Duration::ZERO);
+        let config = resolv_conf.get();
+        assert_eq!(config.nameservers.len(), 1);
+        assert_eq!(config.nameservers[0].to_string(), "10.0.0.9".to_string());
+
+        let resolv_conf = ResolvConf::new(vec![stub.clone()], Duration::ZERO);
+        let config = resolv_conf.get();
+        assert!(config.nameservers.is_empty());
+        assert!(config.edns0);
+
+        std::fs::remove_file(&stub).unwrap();
+        std::fs::remove_file(&upstream).unwrap();
+    }
+    /* -------------------------------------------- */
+    // --------- Test aardvark-dns upstream health ---------
+    /* -------------------------------------------- */
+    #[test]
+    // Failing upstreams go last, healthy ones keep their configured
+    // order whatever their latency.
+    fn test_upstream_health_order() {
+        let health = UpstreamHealth::new();
+        let all = nameservers(&["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:53"]);
+        let (first, second, third) = (&all[0], &all[1], &all[2]);
+        assert_eq!(health.order(&all), all);
+
+        health.record_failure(first);
+        assert_eq!(
+            health.order(&all),
+            vec![second.clone(), third.clone(), first.clone()]
+        );
+
+        health.record_success(second, Duration::from_millis(50));
+        health.record_success(third, Duration::from_millis(5));
+        assert_eq!(
+            health.order(&all),
+            vec![second.clone(), third.clone(), first.clone()]
+        );
+
+        // a success ends the backoff
+        health.record_success(first, Duration::from_millis(100));
+        assert_eq!(health.order(&all), all);
+
+        // among failing upstreams fewer failures go first
+        health.record_failure(first);
+        health.record_failure(first);
+        health.record_failure(second);
+        assert_eq!(
+            health.order(&all),
+            vec![third.clone(), second.clone(), first.clone()]
+        );
+    }
+    /* -------------------------------------------- */
+    // --------- Test aardvark-dns upstream pool ---------
+    /* -------------------------------------------- */
+    #[test]
+    // Clients are reused across runtimes and queries with other
+    // timeouts, they are only rebuilt once discarded.
+    fn test_upstream_client_pool() {
+        let pool = ClientPool::new(tls::client_config(None).unwrap()).unwrap();
+        let nameserver = Nameserver::dns("127.0.0.1:53".parse().unwrap());
+        for _ in 0..2 {
+            // every listener runs its own runtime
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                pool.client(&nameserver, Transport::Udp, Duration::from_secs(1))
+                    .await
+                    .unwrap();
+                pool.client(&nameserver, Transport::Udp, Duration::from_secs(2))
+                    .await
+                    .unwrap();
+            });
+            assert_eq!(pool.len(), 1);
+        }
+
+        pool.discard(&nameserver, Transport::Udp);
+        assert!(pool.is_empty());
+    }
+    /* -------------------------------------------- */
+    // --------- Test aardvark-dns query coalescing ---------
+    /* -------------------------------------------- */
+    #[test]
+    // Identical questions wait for the first one and get its
+    // answer with their own id, other questions are forwarded.
+    fn test_inflight_coalescing() {
+        let inflight = InFlight::new();
+        let req = cache_query(1, "example.com.");
+        let leader = match inflight.join(&req, &upstreams()) {
+            Flight::Lead(leader) => leader,
+            Flight::Follow(_) => panic!("first query must lead"),
+        };
+        let follower = match inflight.join(&cache_query(2, "EXAMPLE.com."), &upstreams()) {
+            Flight::Follow(follower) => follower,
+            Flight::Lead(_) => panic!("identical query must follow"),
+        };
+        assert!(matches!(
+            inflight.join(&cache_query(3, "example.org."), &upstreams()),
+            Flight::Lead(_)
+        ));
+        assert_eq!(inflight.len(), 1);
+
+        leader.complete(&cache_answer(&req, 60));
+        assert!(inflight.is_empty());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let resp = runtime
+            .block_on(follower.wait(&cache_query(2, "EXAMPLE.com.")))
+            .unwrap();
+        assert_eq!(resp.id(), 2);
+        assert_eq!(resp.queries()[0].name().to_string(), "EXAMPLE.com.");
+        assert_eq!(resp.answers().len(), 1);
+
+        // followers of a leader giving up forward on their own
+        let leader = inflight.join(&req, &upstreams());
+        let follower = match inflight.join(&req, &upstreams()) {
+            Flight::Follow(follower) => follower,
+            Flight::Lead(_) => panic!("identical query must follow"),
+        };
+        drop(leader);
+        assert!(runtime.block_on(follower.wait(&req)).is_none());
+    }
+
+    /* -------------------------------------------- */
+    // --------- Test aardvark-dns DNSSEC validation ---------
+    /* -------------------------------------------- */
+    const DNSSEC_ALGORITHM: Algorithm = Algorithm::ECDSAP256SHA256;
+    // A zone of the test hierarchy with its signing key.
+    struct SignedZone {
+        origin: Name,
+        key: KeyPair<Private>,
+        dnskey: DNSKEY,
+    }
+    impl SignedZone {
+        fn new(origin: &str) -> SignedZone {
+            let pkcs8 = KeyPair::<Private>::generate_pkcs8(DNSSEC_ALGORITHM).unwrap();
+            let key = KeyFormat::Pkcs8
+                .decode_key(&pkcs8, None, DNSSEC_ALGORITHM)
+                .unwrap();
+            let dnskey = key.to_dnskey(DNSSEC_ALGORITHM).unwrap();
+            SignedZone {
+                origin: Name::from_ascii(origin).unwrap(),
+                key,
+                dnskey,
+            }
+        }
+        fn ds_record(&self) -> Record {
+            let ds = self
+                .key
+                .to_ds(&self.origin, DNSSEC_ALGORITHM, DigestType::SHA256)
+                .unwrap();
+            Record::from_rdata(self.origin.clone(), 300, RData::DNSSEC(DNSSECRData::DS(ds)))
+        }
+        // records of a single RRset followed by their RRSIG
+        fn sign(&self, mut records: Vec<Record>) -> Vec<Record> {
+            let (name, record_type) = (records[0].name().clone(), records[0].rr_type());
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            let sig = SIG::new(
+                record_type,
+                DNSSEC_ALGORITHM,
+                name.num_labels(),
+                300,
+                now + 3600,
+                now - 3600,
+                self.dnskey.calculate_key_tag().unwrap(),
+                self.origin.clone(),
+                Vec::new(),
+            );
+            let tbs = tbs::rrset_tbs_with_sig(&name, DNSClass::IN, &sig, &records).unwrap();
+            let sig = sig.set_sig(self.key.sign(DNSSEC_ALGORITHM, &tbs).unwrap());
+            records.push(
+                Record::new()
+                    .set_name(name)
+                    .set_ttl(300)
+                    .set_rr_type(RecordType::DNSSEC(DNSSECRecordType::RRSIG))
+                    .set_dns_class(DNSClass::IN)
+                    .set_rdata(RData::DNSSEC(DNSSECRData::SIG(sig)))
+                    .clone(),
+            );
+            records
+        }
+        fn signed_dnskey(&self) -> Vec<Record> {
+            self.sign(vec![Record::from_rdata(
+                self.origin.clone(),
+                300,
+                RData::DNSSEC(DNSSECRData::DNSKEY(self.dnskey.clone())),
+            )])
+        }
+        fn signed_soa(&self) -> Vec<Record> {
+            let zone = Zone::new(&self.origin.to_string(), 1, 60).unwrap();
+            let mut soa = zone.soa_record();
+            soa.set_ttl(300);
+            self.sign(vec![soa])
+        }
+        fn signed_nsec(&self, owner: &str, next: &str, types: &[RecordType]) -> Vec<Record> {
+            let nsec = NSEC::new_cover_self(dnssec_name(next), types.to_vec());
+            self.sign(vec![Record::from_rdata(
+                dnssec_name(owner),
+                300,
+                RData::DNSSEC(DNSSECRData::NSEC(nsec)),
+            )])
+        }
+    }
+    fn dnssec_name(name: &str) -> Name {
+        Name::from_ascii(name).unwrap()
+    }
+    // Answer as received from an upstream resolver.
+    fn dnssec_response(
+        name: &str,
+        record_type: RecordType,
+        code: ResponseCode,
+        answers: Vec<Record>,
+        authority: Vec<Record>,
+    ) -> Message {
+        let mut resp = Message::new();
+        resp.set_message_type(MessageType::Response)
+            .set_response_code(code)
+            .add_query(Query::query(dnssec_name(name), record_type))
+            .add_answers(answers)
+            .add_name_servers(authority);
+        Message::from_vec(&resp.to_vec().unwrap()).unwrap()
+    }
+    // Stub authority for the test hierarchy: the signed zone test. with
+    // the signed child secure.test. and the unsigned child insecure.test.
+    struct StubAuthority {
+        responses: HashMap<(Name, RecordType), Message>,
+        lookups: AtomicUsize,
+    }
+    impl Lookup for StubAuthority {
+        fn lookup(&self, name: &Name, record_type: RecordType) -> BoxFuture<'_, Option<Message>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            let resp = self.responses.get(&(name.clone(), record_type)).cloned();
+            Box::pin(async move { resp })
+        }
+    }
+    struct TestHierarchy {
+        parent: SignedZone,
+        child: SignedZone,
+        authority: StubAuthority,
+        anchor: String,
+        validator: Validator,
+    }
+    fn dnssec_hierarchy() -> TestHierarchy {
+        let parent = SignedZone::new("test.");
+        let child = SignedZone::new("secure.test.");
+        let dnskey = RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+        let ds = RecordType::DNSSEC(DNSSECRecordType::DS);
+
+        let mut responses = HashMap::new();
+        for zone in [&parent, &child] {
+            responses.insert(
+                (zone.origin.clone(), dnskey),
+                dnssec_response(
+                    &zone.origin.to_string(),
+                    dnskey,
+                    ResponseCode::NoError,
+                    zone.signed_dnskey(),
+                    vec![],
+                ),
+            );
+        }
+        responses.insert(
+            (child.origin.clone(), ds),
+            dnssec_response(
+                "secure.test.",
+                ds,
+                ResponseCode::NoError,
+                parent.sign(vec![child.ds_record()]),
+                vec![],
+            ),
+        );
+        // insecure.test. is delegated without DS records
+        let mut authority = parent.signed_soa();
+        authority.extend(parent.signed_nsec("insecure.test.", "secure.test.", &[RecordType::NS]));
+        responses.insert(
+            (dnssec_name("insecure.test."), ds),
+            dnssec_response(
+                "insecure.test.",
+                ds,
+                ResponseCode::NoError,
+                vec![],
+                authority,
+            ),
+        );
+
+        let ds_record = parent.ds_record();
+        let ds = match ds_record.rdata() {
+            RData::DNSSEC(DNSSECRData::DS(ds)) => ds,
+            _ => unreachable!(),
+        };
+        let anchor = format!(
+            "; test anchor\ntest. 3600 IN DS {} {} 2 {}\n",
+            ds.key_tag(),
+            u8::from(ds.algorithm()),
+            data_encoding::HEXUPPER.encode(ds.digest())
+        );
+        TestHierarchy {
+            parent,
+            child,
+            authority: StubAuthority {
+                responses,
+                lookups: AtomicUsize::new(0),
+            },
+            validator: Validator::new(TrustAnchors::parse(&anchor).unwrap()),
+            anchor,
+        }
+    }
+    fn validate(hierarchy: &TestHierarchy, resp: &Message) -> Security {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(hierarchy.validator.validate(&hierarchy.authority, resp))
+    }
+    // SOA and the NSEC3 chain of the apex and www of zone, see
+    // test_dnssec_nsec3_denial.
+    fn nsec3_authority(zone: &SignedZone, iterations: u16) -> Vec<Record> {
+        let salt = vec![0xab, 0xcd];
+        let hash = |name: &str| {
+            Nsec3HashAlgorithm::SHA1
+                .hash(&salt, &dnssec_name(name), iterations)
+                .unwrap()
+                .as_ref()
+                .to_vec()
+        };
+        let names = [zone.origin.to_string(), format!("www.{}", zone.origin)];
+        let mut authority = zone.signed_soa();
+        for (i, name) in names.iter().enumerate() {
+            let nsec3 = NSEC3::new(
+                Nsec3HashAlgorithm::SHA1,
+                false,
+                iterations,
+                salt.clone(),
+                hash(&names[(i + 1) % names.len()]),
+                vec![RecordType::A],
+            );
+            let owner = format!(
+                "{}.{}",
+                data_encoding::BASE32_DNSSEC.encode(&hash(name)),
+                zone.origin
+            );
+            authority.extend(zone.sign(vec![Record::from_rdata(
+                dnssec_name(&owner),
+                300,
+                RData::DNSSEC(DNSSECRData::NSEC3(nsec3)),
+            )]));
+        }
+        authority
+    }
+    fn is_bogus(security: Security) -> bool {
+        matches!(security, Security::Bogus(_))
+    }
+    #[test]
+    // Trust anchors are read from DS and DNSKEY records, the
+    // built-in ones are the key signing keys of the root zone.
+    fn test_dnssec_trust_anchors() {
+        assert_eq!(TrustAnchors::root().len(), 2);
+        let anchors = TrustAnchors::parse(
+            "example. IN DS 12345 13 2 0123456789ABCDEF ; comment\n\
+             ; only a comment\n\
+             example. 300 IN DNSKEY 257 3 13 AAECAwQF\n",
+        )
+        .unwrap();
+        assert_eq!(anchors.len(), 2);
+        assert!(TrustAnchors::parse("; nothing\n").is_err());
+        assert!(TrustAnchors::parse("example. IN A 10.0.0.1\n").is_err());
+        assert!(TrustAnchors::parse("example IN DS 12345 13 2 0123\n").is_err());
+        assert!(TrustAnchors::parse("example. IN DNSKEY 257 2 13 AAECAwQF\n").is_err());
+    }
+    #[test]
+    // Signed answers are secure, modified or unsigned ones bogus
+    // and answers outside of the trust anchors insecure.
+    fn test_dnssec_validate_answers() {
+        let hierarchy = dnssec_hierarchy();
+        let www = Record::from_rdata(
+            dnssec_name("www.secure.test."),
+            300,
+            RData::A("10.0.0.1".parse().unwrap()),
+        );
+        let signed = hierarchy.child.sign(vec![www.clone()]);
+        let resp = |answers| {
+            dnssec_response(
+                "www.secure.test.",
+                RecordType::A,
+                ResponseCode::NoError,
+                answers,
+                vec![],
+            )
+        };
+        assert_eq!(
+            validate(&hierarchy, &resp(signed.clone())),
+            Security::Secure
+        );
+
+        let mut tampered = signed.clone();
+        tampered[0].set_rdata(RData::A("10.0.0.2".parse().unwrap()));
+        assert!(is_bogus(validate(&hierarchy, &resp(tampered))));
+        assert!(is_bogus(validate(&hierarchy, &resp(vec![www]))));
+
+        let outside = dnssec_response(
+            "example.org.",
+            RecordType::A,
+            ResponseCode::NoError,
+            vec![Record::from_rdata(
+                dnssec_name("example.org."),
+                300,
+                RData::A("10.0.0.3".parse().unwrap()),
+            )],
+            vec![],
+        );
+        assert_eq!(validate(&hierarchy, &outside), Security::Insecure);
+    }
+    #[test]
+    // Authenticated DNSKEYs are kept until their records expire, the
+    // chain of trust is not looked up again for every answer.
+    fn test_dnssec_caches_zone_keys() {
+        let mut hierarchy = dnssec_hierarchy();
+        let signed = hierarchy.child.sign(vec![Record::from_rdata(
+            dnssec_name("www.secure.test."),
+            300,
+            RData::A("10.0.0.1".parse().unwrap()),
+        )]);
+        let resp = dnssec_response(
+            "www.secure.test.",
+            RecordType::A,
+            ResponseCode::NoError,
+            signed,
+            vec![],
+        );
+        let lookups =
+            |hierarchy: &TestHierarchy| hierarchy.authority.lookups.load(Ordering::SeqCst);
+
+        assert_eq!(validate(&hierarchy, &resp), Security::Secure);
+        // DNSKEY of test., DS and DNSKEY of secure.test.
+        assert_eq!(lookups(&hierarchy), 3);
+        assert_eq!(validate(&hierarchy, &resp), Security::Secure);
+        assert_eq!(lookups(&hierarchy), 3);
+
+        // keys of secure.test. with a ttl of one second
+        let dnskey = RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+        let mut keys = hierarchy.child.signed_dnskey();
+        keys[0].set_ttl(1);
+        let short = dnssec_response("secure.test.", dnskey, ResponseCode::NoError, keys, vec![]);
+        hierarchy
+            .authority
+            .responses
+            .insert((dnssec_name("secure.test."), dnskey), short);
+        hierarchy.validator = Validator::new(TrustAnchors::parse(&hierarchy.anchor).unwrap());
+        hierarchy.authority.lookups.store(0, Ordering::SeqCst);
+        assert_eq!(validate(&hierarchy, &resp), Security::Secure);
+        assert_eq!(lookups(&hierarchy), 3);
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(validate(&hierarchy, &resp), Security::Secure);
+        // only the expired keys of secure.test. and their DS
+        assert_eq!(lookups(&hierarchy), 5);
+    }
+    #[test]
+    // Unsigned answers are insecure only below a delegation which
+    // is proven to have no DS records.
+    fn test_dnssec_insecure_delegation() {
+        let mut hierarchy = dnssec_hierarchy();
+        let resp = dnssec_response(
+            "host.insecure.test.",
+            RecordType::A,
+            ResponseCode::NoError,
+            vec![Record::from_rdata(
+                dnssec_name("host.insecure.test."),
+                300,
+                RData::A("10.0.0.4".parse().unwrap()),
+            )],
+            vec![],
+        );
+        assert_eq!(validate(&hierarchy, &resp), Security::Insecure);
+
+        // without the NSEC record the missing DS is not proven
+        let ds = RecordType::DNSSEC(DNSSECRecordType::DS);
+        let soa = hierarchy.parent.signed_soa();
+        hierarchy.authority.responses.insert(
+            (dnssec_name("insecure.test."), ds),
+            dnssec_response("insecure.test.", ds, ResponseCode::NoError, vec![], soa),
+        );
+        assert!(is_bogus(validate(&hierarchy, &resp)));
+    }
+    #[test]
+    // Negative answers need NSEC records proving the name or
+    // type does not exist.
+    fn test_dnssec_nsec_denial() {
+        let hierarchy = dnssec_hierarchy();
+        let child = &hierarchy.child;
+        let mut authority = child.signed_soa();
+        authority.extend(child.signed_nsec(
+            "secure.test.",
+            "www.secure.test.",
+            &[RecordType::SOA, RecordType::NS],
+        ));
+        let nxdomain = |authority| {
+            dnssec_response(
+                "missing.secure.test.",
+                RecordType::A,
+                ResponseCode::NXDomain,
+                vec![],
+                authority,
+            )
+        };
+        assert_eq!(validate(&hierarchy, &nxdomain(authority)), Security::Secure);
+        assert!(is_bogus(validate(
+            &hierarchy,
+            &nxdomain(child.signed_soa())
+        )));
+
+        // an NSEC of the name itself proves the missing type only
+        let mut authority = child.signed_soa();
+        authority.extend(child.signed_nsec("www.secure.test.", "secure.test.", &[RecordType::A]));
+        let nodata = |record_type| {
+            dnssec_response(
+                "www.secure.test.",
+                record_type,
+                ResponseCode::NoError,
+                vec![],
+                authority.clone(),
+            )
+        };
+        assert_eq!(
+            validate(&hierarchy, &nodata(RecordType::AAAA)),
+            Security::Secure
+        );
+        assert!(is_bogus(validate(&hierarchy, &nodata(RecordType::A))));
+    }
+    #[test]
+    // NXDOMAIN answers of NSEC3 zones need the closest encloser
+    // proof, see RFC 5155.
+    fn test_dnssec_nsec3_denial() {
+        let hierarchy = dnssec_hierarchy();
+        let child = &hierarchy.child;
+        let authority = |iterations| nsec3_authority(child, iterations);
+        let nxdomain = |authority: Vec<Record>| {
+            dnssec_response(
+                "missing.secure.test.",
+                RecordType::A,
+                ResponseCode::NXDomain,
+                vec![],
+                authority,
+            )
+        };
+        assert_eq!(
+            validate(&hierarchy, &nxdomain(authority(1))),
+            Security::Secure
+        );
+        // without the NSEC3 of the apex there is no closest encloser
+        let mut partial = authority(1);
+        partial.drain(2..4);
+        assert!(is_bogus(validate(&hierarchy, &nxdomain(partial))));
+        // too expensive to check
+        assert_eq!(
+            validate(&hierarchy, &nxdomain(authority(200))),
+            Security::Insecure
+        );
+    }
+    /* -------------------------------------------- */
+    // --------- Test aardvark-dns server ---------
+    /* -------------------------------------------- */
+    // Answer of a stub upstream resolver to a query, the flag tells if it
+    // came over tcp. None leaves the query unanswered.
+    type StubHandler = Arc<dyn Fn(&Message, bool) -> Option<Message> + Send + Sync>;
+    // Serve handler over udp and tcp on a free port of ip.
+    fn stub_upstream(ip: &str, handler: StubHandler) -> SocketAddr {
+        counting_stub_upstream(ip, handler, Arc::new(AtomicUsize::new(0)))
+    }
+    // stub_upstream counting the accepted tcp connections.
+    fn counting_stub_upstream(
+        ip: &str,
+        handler: StubHandler,
+        tcp_connections: Arc<AtomicUsize>,
+    ) -> SocketAddr {
+        let udp = UdpSocket::bind((ip, 0)).unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).unwrap();
+        let udp_handler = handler.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok((len, src)) = udp.recv_from(&mut buf) {
+                let req = Message::from_vec(&buf[..len]).unwrap();
+                if let Some(resp) = udp_handler(&req, false) {
+                    udp.send_to(&resp.to_vec().unwrap(), src).unwrap();
+                }
+            }
+        });
+        std::thread::spawn(move || {
+            for stream in tcp.incoming() {
+                let mut stream = stream.unwrap();
+                tcp_connections.fetch_add(1, Ordering::SeqCst);
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    while let Some(req) = read_tcp_message(&mut stream) {
+                        if let Some(resp) = handler(&req, true) {
+                            write_tcp_message(&mut stream, &resp);
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+    // Answer of a stub upstream resolver with a single address.
+    fn stub_answer(req: &Message, addr: &str) -> Message {
+        let mut resp = req.clone();
+        resp.set_message_type(MessageType::Response)
+            .set_recursion_available(true)
+            .add_answer(Record::from_rdata(
+                req.queries()[0].name().clone(),
+                300,
+                RData::A(addr.parse().unwrap()),
+            ));
+        resp
+    }
+    fn read_tcp_message(stream: &mut impl Read) -> Option<Message> {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).ok()?;
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).ok()?;
+        Message::from_vec(&buf).ok()
+    }
+    fn write_tcp_message(stream: &mut impl Write, msg: &Message) {
+        let bytes = msg.to_vec().unwrap();
+        let mut framed = (bytes.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&bytes);
+        let _ = stream.write_all(&framed);
+    }
+    // The defaults of the run command.
+    fn server_options() -> CoreDnsOptions {
+        CoreDnsOptions {
+            ttl: 60,
+            negative_ttl: 10,
+            cache_size: 1000,
+            cache_min_ttl: 0,
+            cache_max_ttl: 86400,
+            serve_stale: 0,
+            prefetch_hits: 3,
+            cache_snapshot: false,
+            upstream_timeout: None,
+            query_timeout: Duration::from_secs(5),
+            resolv_conf_fallback: PathBuf::from("/nonexistent"),
+            fallback_nameserver: "1.1.1.1".parse().unwrap(),
+            tls_ca_file: None,
+            dnssec: false,
+            dnssec_trust_anchor: None,
+            local_zones: LocalZones::builtin(),
+        }
+    }
+    // Free port for a test server. Ports are taken below the ephemeral range
+    // so that the stubs of tests running in parallel, which bind port 0,
+    // cannot take them between the probe and the start of the server.
+    fn server_port() -> u16 {
+        static NEXT_PORT: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let offset = NEXT_PORT.fetch_add(1, Ordering::SeqCst) % 100;
+            let port = (20000 + std::process::id() % 100 * 100) as u16 + offset as u16;
+            // the port is free again once the probes are dropped
+            if UdpSocket::bind(("127.0.0.1", port)).is_ok()
+                && TcpListener::bind(("127.0.0.1", port)).is_ok()
+            {
+                return port;
+            }
+        }
+    }
+    struct TestServer {
+        addr: SocketAddr,
+        dir: PathBuf,
+        _kill: async_broadcast::Sender<bool>,
+    }
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+    // Start a server on a free port of 127.0.0.1 for the network test with
+    // the given config file, resolv_conf replaces the host's resolv.conf.
+    // The search domain is the default .dns.podman, DNSSEC validation uses
+    // the trust anchor file of options only.
+    fn start_server(
+        name: &str,
+        config: &str,
+        resolv_conf: &str,
+        options: CoreDnsOptions,
+    ) -> TestServer {
+        let dir =
+            std::env::temp_dir().join(format!("aardvark-server-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("config")).unwrap();
+        std::fs::write(dir.join("config").join("test"), config).unwrap();
+        std::fs::write(dir.join("resolv.conf"), resolv_conf).unwrap();
+        let (backend, _, _) = config::parse_configs(dir.join("config").to_str().unwrap()).unwrap();
+        let upstreams = Upstreams {
+            resolv_conf: Arc::new(ResolvConf::new(
+                vec![dir.join("resolv.conf")],
+                Duration::from_secs(60),
+            )),
+            cache: Arc::new(ResponseCache::new(
+                options.cache_size,
+                options.cache_min_ttl,
+                options.cache_max_ttl,
+                options.serve_stale,
+                options.prefetch_hits,
+            )),
+            health: Arc::new(UpstreamHealth::new()),
+            pool: Arc::new(
+                ClientPool::new(tls::client_config(options.tls_ca_file.as_deref()).unwrap())
+                    .unwrap(),
+            ),
+            inflight: Arc::new(InFlight::new()),
+            dnssec: options.dnssec.then(|| {
+                let anchors = options.dnssec_trust_anchor.as_deref().unwrap();
+                Arc::new(Validator::new(TrustAnchors::from_file(anchors).unwrap()))
+            }),
+        };
+
+        let port = server_port();
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        let (tx, rx) = async_broadcast::broadcast(1);
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let server = CoreDns::new(
+                    addr.ip(),
+                    port as u32,
+                    "test",
+                    "192.0.2.1".parse().unwrap(),
+                    53,
+                    Arc::new(backend),
+                    Arc::new(Mutex::new(false)),
+                    ".dns.podman".to_string(),
+                    rx,
+                    options,
+                    upstreams,
+                )
+                .await
+                .unwrap();
+                server.run().await.unwrap();
+            });
+        });
+        // the tcp listener is up after the udp one
+        let start = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "server did not start"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        TestServer {
+            addr,
+            dir,
+            _kill: tx,
+        }
+    }
+    fn server_query(name: &str, record_type: RecordType, payload: Option<u16>) -> Message {
+        let mut req = Message::new();
+        req.set_id(rand_id())
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), record_type));
+        if let Some(payload) = payload {
+            let mut edns = Edns::new();
+            edns.set_max_payload(payload).set_version(0);
+            req.set_edns(edns);
+        }
+        req
+    }
+    fn rand_id() -> u16 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos() as u16
+    }
+    // Send req to server over udp, returns the answer and its size.
+    fn udp_exchange(server: &TestServer, req: &Message) -> (Message, usize) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(15)))
+            .unwrap();
+        socket.send_to(&req.to_vec().unwrap(), server.addr).unwrap();
+        let mut buf = [0u8; 65535];
+        let len = socket.recv(&mut buf).unwrap();
+        (Message::from_vec(&buf[..len]).unwrap(), len)
+    }
+    fn tcp_exchange(server: &TestServer, req: &Message) -> Message {
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(15)))
+            .unwrap();
+        write_tcp_message(&mut stream, req);
+        read_tcp_message(&mut stream).unwrap()
+    }
+    #[test]
+    // Queries pipelined on one tcp connection are answered independently,
+    // a slow forwarded one does not hold back a local one sent after it.
+    fn test_server_tcp_pipelining() {
+        let slow = stub_upstream(
+            "127.0.0.1",
+            Arc::new(|req, _| {
+                std::thread::sleep(Duration::from_millis(300));
+                Some(stub_answer(req, "192.0.2.40"))
+            }),
+        );
+        let server = start_server(
+            "pipelining",
+            &format!("127.0.0.1 dns={}\n7b46c7ad93fc 10.89.0.2  aone\n", slow),
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let forwarded = server_query("example.com.", RecordType::A, None);
+        let local = server_query("aone.dns.podman.", RecordType::A, None);
+        let mut framed = Vec::new();
+        write_tcp_message(&mut framed, &forwarded);
+        write_tcp_message(&mut framed, &local);
+
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(&framed).unwrap();
+        let first = read_tcp_message(&mut stream).unwrap();
+        let second = read_tcp_message(&mut stream).unwrap();
+        assert_eq!(first.id(), local.id());
+        assert_eq!(
+            first.answers()[0].rdata(),
+            &RData::A("10.89.0.2".parse().unwrap())
+        );
+        assert_eq!(second.id(), forwarded.id());
+        assert_eq!(
+            second.answers()[0].rdata(),
+            &RData::A("192.0.2.40".parse().unwrap())
+        );
+    }
+    #[test]
+    // The server closes tcp connections which stay idle for 10 seconds.
+    fn test_server_closes_idle_tcp_connections() {
+        let server = start_server(
+            "tcpidle",
+            "127.0.0.1\n7b46c7ad93fc 10.89.0.2  aone\n",
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(15)))
+            .unwrap();
+        write_tcp_message(
+            &mut stream,
+            &server_query("aone.dns.podman.", RecordType::A, None),
+        );
+        assert!(read_tcp_message(&mut stream).is_some());
+
+        let start = Instant::now();
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(9), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(12), "{:?}", elapsed);
+    }
+    #[test]
+    // udp answers must fit into 512 bytes or the EDNS payload size of the
+    // client clamped to 512..=4096, tcp answers are never truncated.
+    fn test_server_truncates_udp_answers() {
+        let mut config = "127.0.0.1\n".to_string();
+        for i in 0..300 {
+            config.push_str(&format!(
+                "{:064x} 10.89.{}.{}  ctr{},big\n",
+                i,
+                i / 250,
+                i % 250 + 1,
+                i
+            ));
+        }
+        let server = start_server(
+            "truncate",
+            &config,
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+
+        let (resp, len) = udp_exchange(&server, &server_query("big.", RecordType::A, None));
+        assert!(resp.truncated());
+        assert!(len <= 512);
+        assert!(!resp.answers().is_empty());
+        // advertised payload sizes below 512 are raised to it
+        let (resp, len) = udp_exchange(&server, &server_query("big.", RecordType::A, Some(100)));
+        assert!(resp.truncated());
+        assert!((400..=512).contains(&len));
+        let (resp, len) = udp_exchange(&server, &server_query("big.", RecordType::A, Some(2048)));
+        assert!(resp.truncated());
+        assert!((1900..=2048).contains(&len));
+        // and sizes above 4096 lowered to it
+        let (resp, len) = udp_exchange(&server, &server_query("big.", RecordType::A, Some(65000)));
+        assert!(resp.truncated());
+        assert!((3900..=4096).contains(&len));
+
+        let resp = tcp_exchange(&server, &server_query("big.", RecordType::A, Some(4096)));
+        assert!(!resp.truncated());
+        assert_eq!(resp.answers().len(), 300);
+
+        // everything fits in 4096 bytes
+        let (resp, _) = udp_exchange(&server, &server_query("ctr7.", RecordType::A, None));
+        assert!(!resp.truncated());
+        assert_eq!(resp.answers().len(), 1);
+        let mut config = "127.0.0.1\n".to_string();
+        for i in 0..60 {
+            config.push_str(&format!("{:064x} 10.89.0.{}  ctr{},web\n", i, i + 1, i));
+        }
+        let server = start_server(
+            "truncate60",
+            &config,
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let (resp, len) = udp_exchange(&server, &server_query("web.", RecordType::A, None));
+        assert!(resp.truncated());
+        assert!(len <= 512);
+        let (resp, _) = udp_exchange(&server, &server_query("web.", RecordType::A, Some(4096)));
+        assert!(!resp.truncated());
+        assert_eq!(resp.answers().len(), 60);
+    }
+    #[test]
+    // Only negative answers for names in the search domain are
+    // authoritative and carry the SOA of the zone.
+    fn test_server_negative_answers() {
+        let server = start_server(
+            "negative",
+            "127.0.0.1\n7b46c7ad93fc 10.89.0.2  aone\n",
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let (resp, _) = udp_exchange(&server, &server_query("nosuch.", RecordType::A, None));
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(!resp.authoritative());
+        assert!(resp.name_servers().is_empty());
+
+        let query = server_query("nosuch.dns.podman.", RecordType::A, None);
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.authoritative());
+        assert_eq!(resp.name_servers()[0].record_type(), RecordType::SOA);
+
+        let query = server_query("aone.dns.podman.", RecordType::AAAA, None);
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(resp.answers().is_empty());
+        assert!(resp.authoritative());
+        assert_eq!(resp.name_servers()[0].record_type(), RecordType::SOA);
+        let (resp, _) = udp_exchange(&server, &server_query("aone.", RecordType::AAAA, None));
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(!resp.authoritative());
+        assert!(resp.name_servers().is_empty());
+    }
+    #[test]
+    // Requesters outside of the container networks resolve the addresses
+    // of the server's network like its names, other addresses of its
+    // subnets do not exist.
+    fn test_server_reverse_lookup_from_host() {
+        let server = start_server(
+            "reverse",
+            "10.89.0.1 subnet=10.89.0.0/24\n7b46c7ad93fc 10.89.0.2  aone\n",
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let (resp, _) = udp_exchange(&server, &server_query("aone.", RecordType::A, None));
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::A("10.89.0.2".parse().unwrap())
+        );
+
+        let query = server_query("2.0.89.10.in-addr.arpa.", RecordType::PTR, None);
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(resp.authoritative());
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::PTR(Name::from_ascii("aone.").unwrap())
+        );
+
+        let query = server_query("3.0.89.10.in-addr.arpa.", RecordType::PTR, None);
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.authoritative());
+        assert_eq!(resp.name_servers()[0].record_type(), RecordType::SOA);
+    }
+    #[test]
+    // Reverse zones of private addresses are answered locally, unless
+    // the network has dns= servers which may serve them.
+    fn test_server_forwards_private_reverse_zones() {
+        let upstream = stub_upstream(
+            "127.0.0.1",
+            Arc::new(|req, _| {
+                let mut resp = req.clone();
+                resp.set_message_type(MessageType::Response)
+                    .add_answer(Record::from_rdata(
+                        req.queries()[0].name().clone(),
+                        300,
+                        RData::PTR(Name::from_ascii("host.corp.example.").unwrap()),
+                    ));
+                Some(resp)
+            }),
+        );
+        let query = server_query("5.0.0.10.in-addr.arpa.", RecordType::PTR, None);
+        let server = start_server(
+            "privatereverse",
+            &format!("127.0.0.1 dns={}\n", upstream),
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::PTR(Name::from_ascii("host.corp.example.").unwrap())
+        );
+        // other special-use names stay local
+        let (resp, _) = udp_exchange(&server, &server_query("app.test.", RecordType::A, None));
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.authoritative());
+
+        let server = start_server(
+            "privatereverselocal",
+            "127.0.0.1\n",
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.authoritative());
+    }
+    // Extended DNS Error info code of resp, see RFC 8914.
+    fn ede_info_code(resp: &Message) -> Option<u16> {
+        match resp.edns()?.option(EdnsCode::Unknown(15))? {
+            EdnsOption::Unknown(_, data) => Some(u16::from_be_bytes([data[0], data[1]])),
+            _ => None,
+        }
+    }
+    #[test]
+    // Without any answer in time the client gets SERVFAIL with the
+    // reason as Extended DNS Error once the query timeout is over.
+    fn test_server_servfail_on_timeout() {
+        let blackhole = stub_upstream("127.0.0.1", Arc::new(|_, _| None));
+        let mut options = server_options();
+        options.query_timeout = Duration::from_secs(2);
+        let server = start_server(
+            "timeout",
+            &format!("127.0.0.1 dns={}\n", blackhole),
+            "nameserver 192.0.2.53\n",
+            options,
+        );
+        let start = Instant::now();
+        let (resp, _) = udp_exchange(
+            &server,
+            &server_query("example.com.", RecordType::A, Some(1232)),
+        );
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+        assert_eq!(resp.response_code(), ResponseCode::ServFail);
+        assert_eq!(ede_info_code(&resp), Some(22));
+
+        // clients without EDNS only get SERVFAIL
+        let (resp, _) = udp_exchange(&server, &server_query("example.org.", RecordType::A, None));
+        assert_eq!(resp.response_code(), ResponseCode::ServFail);
+        assert!(resp.edns().is_none());
+    }
+    #[test]
+    // A dead resolver must not use up the whole query timeout, the next
+    // resolver and further attempts need their turn as well.
+    fn test_server_fails_over_within_query_timeout() {
+        let blackhole = stub_upstream("127.0.0.1", Arc::new(|_, _| None));
+        let live = stub_upstream(
+            "127.0.0.1",
+            Arc::new(|req, _| Some(stub_answer(req, "192.0.2.10"))),
+        );
+        let server = start_server(
+            "failover",
+            &format!("127.0.0.1 dns={},{}\n", blackhole, live),
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let start = Instant::now();
+        let (resp, _) = udp_exchange(&server, &server_query("example.com.", RecordType::A, None));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::A("192.0.2.10".parse().unwrap())
+        );
+
+        // the only resolver answers the second attempt
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        let flaky = stub_upstream(
+            "127.0.0.1",
+            Arc::new(move |req, _| {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return None;
+                }
+                Some(stub_answer(req, "192.0.2.11"))
+            }),
+        );
+        let server = start_server(
+            "attempts",
+            &format!("127.0.0.1 dns={}\n", flaky),
+            "nameserver 192.0.2.53\noptions attempts:2\n",
+            server_options(),
+        );
+        let start = Instant::now();
+        let (resp, _) = udp_exchange(&server, &server_query("example.com.", RecordType::A, None));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+    // Answer with 40 addresses over tcp, over udp only the first of them
+    // with the TC bit set. With tcp_works false tcp queries stay unanswered.
+    fn truncating_upstream(tcp_works: bool, tcp_queries: Arc<AtomicUsize>) -> SocketAddr {
+        counting_truncating_upstream(tcp_works, tcp_queries, Arc::new(AtomicUsize::new(0)))
+    }
+    // truncating_upstream counting the accepted tcp connections.
+    fn counting_truncating_upstream(
+        tcp_works: bool,
+        tcp_queries: Arc<AtomicUsize>,
+        tcp_connections: Arc<AtomicUsize>,
+    ) -> SocketAddr {
+        counting_stub_upstream(
+            "127.0.0.1",
+            Arc::new(move |req, tcp| {
+                if tcp {
+                    tcp_queries.fetch_add(1, Ordering::SeqCst);
+                    if !tcp_works {
+                        return None;
+                    }
+                }
+                let mut resp = stub_answer(req, "192.0.2.1");
+                if tcp {
+                    for i in 2..=40 {
+                        resp.add_answer(Record::from_rdata(
+                            req.queries()[0].name().clone(),
+                            300,
+                            RData::A(format!("192.0.2.{}", i).parse().unwrap()),
+                        ));
+                    }
+                } else {
+                    resp.set_truncated(true);
+                }
+                Some(resp)
+            }),
+            tcp_connections,
+        )
+    }
+    #[test]
+    // Truncated upstream answers must be fetched again over tcp, if that
+    // fails the next resolver is asked.
+    fn test_server_retries_truncated_answers_over_tcp() {
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+        let truncating = truncating_upstream(true, tcp_queries.clone());
+        let server = start_server(
+            "tcpretry",
+            &format!("127.0.0.1 dns={}\n", truncating),
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let query = server_query("example.com.", RecordType::A, Some(4096));
+        let (resp, _) = udp_exchange(&server, &query);
+        assert!(!resp.truncated());
+        assert_eq!(resp.answers().len(), 40);
+        assert_eq!(tcp_queries.load(Ordering::SeqCst), 1);
+
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+        let broken = truncating_upstream(false, tcp_queries.clone());
+        let live = stub_upstream(
+            "127.0.0.1",
+            Arc::new(|req, _| Some(stub_answer(req, "192.0.2.99"))),
+        );
+        let server = start_server(
+            "tcpretryfail",
+            &format!("127.0.0.1 dns={},{}\n", broken, live),
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(tcp_queries.load(Ordering::SeqCst), 1);
+        assert!(!resp.truncated());
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::A("192.0.2.99".parse().unwrap())
+        );
+
+        // without any other resolver the client gets the truncated answer
+        let mut options = server_options();
+        options.upstream_timeout = Some(Duration::from_secs(1));
+        let server = start_server(
+            "tcpretryonly",
+            &format!("127.0.0.1 dns={}\n", broken),
+            "nameserver 192.0.2.53\n",
+            options,
+        );
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(resp.truncated());
+        assert_eq!(resp.answers().len(), 1);
+    }
+    #[test]
+    // With the rotate option of resolv.conf queries are spread among the
+    // healthy resolvers, even if one of them is faster.
+    fn test_server_rotates_upstreams() {
+        let counters = [Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))];
+        let upstreams: Vec<String> = counters
+            .iter()
+            .map(|counter| {
+                let counter = counter.clone();
+                stub_upstream(
+                    "127.0.0.1",
+                    Arc::new(move |req, _| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        Some(stub_answer(req, "192.0.2.1"))
+                    }),
+                )
+                .to_string()
+            })
+            .collect();
+        let server = start_server(
+            "rotate",
+            &format!("127.0.0.1 dns={}\n", upstreams.join(",")),
+            "nameserver 192.0.2.53\noptions rotate\n",
+            server_options(),
+        );
+        for i in 0..6 {
+            let query = server_query(&format!("host{}.example.com.", i), RecordType::A, None);
+            let (resp, _) = udp_exchange(&server, &query);
+            assert_eq!(resp.response_code(), ResponseCode::NoError);
+        }
+        assert_eq!(counters[0].load(Ordering::SeqCst), 3);
+        assert_eq!(counters[1].load(Ordering::SeqCst), 3);
+    }
+    #[test]
+    // A failed prefetch is retried on the next hit of the answer.
+    fn test_server_retries_failed_prefetch() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        let upstream = stub_upstream(
+            "127.0.0.1",
+            Arc::new(move |req, _| {
+                let mut resp = match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 => stub_answer(req, "192.0.2.1"),
+                    1 => {
+                        let mut resp = req.clone();
+                        resp.set_message_type(MessageType::Response)
+                            .set_response_code(ResponseCode::ServFail);
+                        return Some(resp);
+                    }
+                    _ => stub_answer(req, "192.0.2.2"),
+                };
+                // due for prefetching right away
+                let mut answers = resp.take_answers();
+                answers[0].set_ttl(1);
+                resp.insert_answers(answers);
+                Some(resp)
+            }),
+        );
+        let mut options = server_options();
+        options.prefetch_hits = 1;
+        let server = start_server(
+            "prefetch",
+            &format!("127.0.0.1 dns={}\n", upstream),
+            "nameserver 192.0.2.53\noptions attempts:1\n",
+            options,
+        );
+        let query = server_query("example.com.", RecordType::A, None);
+        let address = |resp: &Message| resp.answers()[0].rdata().clone();
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(address(&resp), RData::A("192.0.2.1".parse().unwrap()));
+
+        // the first hit starts a prefetch which fails, the second one
+        // starts another which replaces the answer
+        for expected in [2, 3] {
+            let (resp, _) = udp_exchange(&server, &query);
+            assert_eq!(address(&resp), RData::A("192.0.2.1".parse().unwrap()));
+            std::thread::sleep(Duration::from_millis(100));
+            assert_eq!(queries.load(Ordering::SeqCst), expected);
+        }
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(address(&resp), RData::A("192.0.2.2".parse().unwrap()));
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+    #[test]
+    // The tcp retries of truncated answers share a single pooled connection
+    // to the resolver.
+    fn test_server_reuses_tcp_connection_for_retries() {
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+        let tcp_connections = Arc::new(AtomicUsize::new(0));
+        let truncating =
+            counting_truncating_upstream(true, tcp_queries.clone(), tcp_connections.clone());
+        let server = start_server(
+            "tcpreuse",
+            &format!("127.0.0.1 dns={}\n", truncating),
+            "nameserver 192.0.2.53\n",
+            server_options(),
+        );
+        for i in 0..5 {
+            let query = server_query(
+                &format!("host{}.example.com.", i),
+                RecordType::A,
+                Some(4096),
+            );
+            let (resp, _) = udp_exchange(&server, &query);
+            assert_eq!(resp.answers().len(), 40);
+        }
+        assert_eq!(tcp_queries.load(Ordering::SeqCst), 5);
+        assert_eq!(tcp_connections.load(Ordering::SeqCst), 1);
+    }
+    // TLS config of stub resolvers with a certificate for dns.example, it is
+    // signed by the CA of src/test/tls/ca.pem.
+    fn stub_tls_config(protocols: &[Vec<u8>]) -> Arc<rustls::ServerConfig> {
+        let pem =
+            |file: &str| BufReader::new(File::open(format!("src/test/tls/{}", file)).unwrap());
+        let certs = rustls::internal::pemfile::certs(&mut pem("server.pem")).unwrap();
+        let mut keys =
+            rustls::internal::pemfile::pkcs8_private_keys(&mut pem("server.key")).unwrap();
+        let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        config.set_single_cert(certs, keys.remove(0)).unwrap();
+        config.set_protocols(protocols);
+        Arc::new(config)
+    }
+    // Serve handler over DNS-over-TLS on a free port of 127.0.0.1.
+    fn stub_tls_upstream(handler: StubHandler) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = stub_tls_config(&[]);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let config = config.clone();
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    let mut session = rustls::ServerSession::new(&config);
+                    let mut tls = rustls::Stream::new(&mut session, &mut stream);
+                    while let Some(req) = read_tcp_message(&mut tls) {
+                        if let Some(resp) = handler(&req, true) {
+                            write_tcp_message(&mut tls, &resp);
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+    #[test]
+    // DNS-over-TLS resolvers are trusted with the CA of --tls-ca-file and
+    // their certificate must be valid for the configured server name.
+    fn test_server_forwards_over_tls() {
+        let upstream = stub_tls_upstream(Arc::new(|req, _| Some(stub_answer(req, "192.0.2.20"))));
+        let mut options = server_options();
+        options.tls_ca_file = Some(PathBuf::from("src/test/tls/ca.pem"));
+        options.query_timeout = Duration::from_secs(2);
+        let server = start_server(
+            "tls",
+            &format!("127.0.0.1 dns=tls://{}#dns.example\n", upstream),
+            "nameserver 192.0.2.53\n",
+            options.clone(),
+        );
+        let query = server_query("example.com.", RecordType::A, Some(1232));
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::A("192.0.2.20".parse().unwrap())
+        );
+
+        let server = start_server(
+            "tlsname",
+            &format!("127.0.0.1 dns=tls://{}#wrong.example\n", upstream),
+            "nameserver 192.0.2.53\n",
+            options,
+        );
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::ServFail);
+        assert_eq!(ede_info_code(&resp), Some(23));
+
+        // the certificate is not signed by a CA of the host
+        let mut options = server_options();
+        options.query_timeout = Duration::from_secs(2);
+        let server = start_server(
+            "tlsca",
+            &format!("127.0.0.1 dns=tls://{}#dns.example\n", upstream),
+            "nameserver 192.0.2.53\n",
+            options,
+        );
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::ServFail);
+        assert_eq!(ede_info_code(&resp), Some(23));
+    }
+    // Serve handler over DNS-over-HTTPS on a free port of 127.0.0.1, every
+    // answer has the given HTTP status.
+    fn stub_https_upstream(status: u16, handler: StubHandler) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(stub_tls_config(&[b"h2".to_vec()]));
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                while let Ok((stream, _)) = listener.accept().await {
+                    let acceptor = acceptor.clone();
+                    let handler = handler.clone();
+                    tokio::spawn(async move {
+                        let tls = match acceptor.accept(stream).await {
+                            Ok(tls) => tls,
+                            Err(_) => return,
+                        };
+                        let mut connection = match h2::server::handshake(tls).await {
+                            Ok(connection) => connection,
+                            Err(_) => return,
+                        };
+                        while let Some(Ok((request, respond))) = connection.accept().await {
+                            tokio::spawn(https_answer(request, respond, status, handler.clone()));
+                        }
+                    });
+                }
+            });
+        });
+        addr
+    }
+    async fn https_answer(
+        request: http::Request<h2::RecvStream>,
+        mut respond: h2::server::SendResponse<bytes::Bytes>,
+        status: u16,
+        handler: StubHandler,
+    ) {
+        let mut body = request.into_body();
+        let mut query = Vec::new();
+        while let Some(Ok(chunk)) = body.data().await {
+            let _ = body.flow_control().release_capacity(chunk.len());
+            query.extend_from_slice(&chunk);
+        }
+        let answer = Message::from_vec(&query)
+            .ok()
+            .and_then(|req| handler(&req, true))
+            .map(|resp| resp.to_vec().unwrap());
+        let response = http::Response::builder()
+            .status(status)
+            .header("content-type", "application/dns-message")
+            .body(())
+            .unwrap();
+        match answer {
+            Some(answer) => {
+                if let Ok(mut send) = respond.send_response(response, false) {
+                    let _ = send.send_data(answer.into(), true);
+                }
+            }
+            None => {
+                let _ = respond.send_response(response, true);
+            }
+        }
+    }
+    #[test]
+    // DNS-over-HTTPS resolvers must answer with a success status and a
+    // certificate valid for the configured server name.
+    fn test_server_forwards_over_https() {
+        let answer: StubHandler = Arc::new(|req, _| Some(stub_answer(req, "192.0.2.30")));
+        let upstream = stub_https_upstream(200, answer.clone());
+        let mut options = server_options();
+        options.tls_ca_file = Some(PathBuf::from("src/test/tls/ca.pem"));
+        options.query_timeout = Duration::from_secs(2);
+        let server = start_server(
+            "https",
+            &format!("127.0.0.1 dns=https://{}/dns-query#dns.example\n", upstream),
+            "nameserver 192.0.2.53\n",
+            options.clone(),
+        );
+        let query = server_query("example.com.", RecordType::A, Some(1232));
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            resp.answers()[0].rdata(),
+            &RData::A("192.0.2.30".parse().unwrap())
+        );
+
+        let failing = stub_https_upstream(500, answer);
+        let server = start_server(
+            "httpsstatus",
+            &format!("127.0.0.1 dns=https://{}#dns.example\n", failing),
+            "nameserver 192.0.2.53\n",
+            options.clone(),
+        );
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::ServFail);
+        assert_eq!(ede_info_code(&resp), Some(23));
+
+        let server = start_server(
+            "httpsname",
+            &format!("127.0.0.1 dns=https://{}#wrong.example\n", upstream),
+            "nameserver 192.0.2.53\n",
+            options,
+        );
+        let (resp, _) = udp_exchange(&server, &query);
+        assert_eq!(resp.response_code(), ResponseCode::ServFail);
+        assert_eq!(ede_info_code(&resp), Some(23));
+    }
+    #[test]
+    // Forwarded answers are validated against a signed zone served by a
+    // stub resolver: secure answers, wildcard expansions and NSEC3 denials
+    // get the AD bit, answers below an insecure delegation are passed on
+    // and answers with a bad or without any RRSIG or a wildcard expansion
+    // without proof are answered with SERVFAIL.
+    fn test_server_validates_dnssec() {
+        let hierarchy = dnssec_hierarchy();
+        let child = &hierarchy.child;
+        let a = |name: &str, addr: &str| {
+            Record::from_rdata(dnssec_name(name), 300, RData::A(addr.parse().unwrap()))
+        };
+        let www = |addr: &str| a("www.secure.test.", addr);
+        let mut bogus = child.sign(vec![www("10.0.0.1")]);
+        bogus[0].set_rdata(RData::A("10.0.0.2".parse().unwrap()));
+        // signed as *.secure.test., the RRSIG has one label less than the name
+        let wildcard = child.sign(vec![a("*.secure.test.", "10.0.0.5")]);
+        // no name between secure.test. and www.secure.test. exists
+        let mut expansion = child.signed_soa();
+        expansion.extend(child.signed_nsec(
+            "secure.test.",
+            "www.secure.test.",
+            &[RecordType::SOA, RecordType::NS],
+        ));
+        let answers = [
+            (
+                "www.secure.test.",
+                child.sign(vec![www("10.0.0.1")]),
+                vec![],
+            ),
+            ("bogus.secure.test.", bogus, vec![]),
+            ("unsigned.secure.test.", vec![www("10.0.0.3")], vec![]),
+            ("any.secure.test.", wildcard.clone(), expansion),
+            ("unproven.secure.test.", wildcard, vec![]),
+            ("host.insecure.test.", vec![www("10.0.0.4")], vec![]),
+        ];
+        let mut responses = hierarchy.authority.responses.clone();
+        for (name, records, authority) in answers {
+            let records = records
+                .into_iter()
+                .map(|mut r| r.set_name(dnssec_name(name)).clone())
+                .collect();
+            responses.insert(
+                (dnssec_name(name), RecordType::A),
+                dnssec_response(
+                    name,
+                    RecordType::A,
+                    ResponseCode::NoError,
+                    records,
+                    authority,
+                ),
+            );
+        }
+        responses.insert(
+            (dnssec_name("missing.secure.test."), RecordType::A),
+            dnssec_response(
+                "missing.secure.test.",
+                RecordType::A,
+                ResponseCode::NXDomain,
+                vec![],
+                nsec3_authority(child, 1),
+            ),
+        );
+        let upstream = stub_upstream(
+            "127.0.0.1",
+            Arc::new(move |req, _| {
+                let query = &req.queries()[0];
+                let mut resp = match responses.get(&(query.name().clone(), query.query_type())) {
+                    Some(resp) => resp.clone(),
+                    None => {
+                        let mut resp = req.clone();
+                        resp.set_message_type(MessageType::Response)
+                            .set_response_code(ResponseCode::Refused);
+                        resp
+                    }
+                };
+                resp.set_id(req.id());
+                Some(resp)
+            }),
+        );
+
+        let anchor = std::env::temp_dir().join(format!("aardvark-anchor-{}", std::process::id()));
+        std::fs::write(&anchor, &hierarchy.anchor).unwrap();
+        let mut options = server_options();
+        options.dnssec = true;
+        options.dnssec_trust_anchor = Some(anchor.clone());
+        // test. is a special-use zone answered locally by default
+        options.local_zones = LocalZones::parse("").unwrap();
+        let server = start_server(
+            "dnssec",
+            &format!("127.0.0.1 dns={}\n", upstream),
+            "nameserver 192.0.2.53\n",
+            options,
+        );
+        let _ = std::fs::remove_file(&anchor);
+        let query = |name: &str| {
+            let mut req = server_query(name, RecordType::A, Some(1232));
+            req.edns_mut().set_dnssec_ok(true);
+            udp_exchange(&server, &req).0
+        };
+
+        let resp = query("www.secure.test.");
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(resp.authentic_data());
+        assert_eq!(resp.answers()[0].rdata(), www("10.0.0.1").rdata());
+
+        let resp = query("any.secure.test.");
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(resp.authentic_data());
+        assert_eq!(resp.answers()[0].name(), &dnssec_name("any.secure.test."));
+
+        let resp = query("host.insecure.test.");
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(!resp.authentic_data());
+        assert_eq!(resp.answers()[0].rdata(), www("10.0.0.4").rdata());
+
+        let resp = query("missing.secure.test.");
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.authentic_data());
+
+        for name in [
+            "bogus.secure.test.",
+            "unsigned.secure.test.",
+            "unproven.secure.test.",
+        ] {
+            let resp = query(name);
+            assert_eq!(resp.response_code(), ResponseCode::ServFail, "{}", name);
+            assert_eq!(ede_info_code(&resp), Some(6), "{}", name);
+        }
+    }
 }
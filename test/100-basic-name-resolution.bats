@@ -23,6 +23,29 @@ load helpers
 	assert "$output" =~ "[0-9]+\.[0-9]+\.[0-9]+\.[0-9]+"
 }
 
+@test "basic container - dns itself over tcp" {
+	setup_slirp4netns
+
+	subnet_a=$(random_subnet 5)
+	create_config "podman1" $(random_string 64) "aone" "$subnet_a" "a1" "1a"
+	config_a1=$config
+	ip_a1=$(echo "$config_a1" | jq -r .networks.podman1.static_ips[0])
+	gw=$(echo "$config_a1" | jq -r .network_info.podman1.subnets[0].gateway)
+	create_container "$config_a1"
+	a1_pid=$CONTAINER_NS_PID
+	run_in_container_netns "$a1_pid" "dig" "+short" "+tcp" "aone" "@$gw"
+	assert "$ip_a1"
+
+	# two queries over the same kept open connection
+	run_in_container_netns "$a1_pid" "dig" "+short" "+tcp" "+keepopen" "aone" "@$gw" "a1" "@$gw"
+	assert "${lines[0]}" == "$ip_a1"
+	assert "${lines[1]}" == "$ip_a1"
+
+	run_in_container_netns "$a1_pid" "dig" "+short" "+tcp" "google.com" "@$gw"
+	# validate that we get an ipv4
+	assert "$output" =~ "[0-9]+\.[0-9]+\.[0-9]+\.[0-9]+"
+}
+
 @test "basic container - ndots incomplete bad entry must NXDOMAIN instead of forwarding and timing out" {
 	setup_slirp4netns
 
@@ -37,6 +60,48 @@ load helpers
 	assert "$output" =~ "NXDOMAIN"
 }
 
+@test "basic container - query for missing record type must return NODATA" {
+	subnet_a=$(random_subnet 5)
+	create_config "podman1" $(random_string 64) "aone" "$subnet_a" "a1" "1a"
+	config_a1=$config
+	ip_a1=$(echo "$config_a1" | jq -r .networks.podman1.static_ips[0])
+	gw=$(echo "$config_a1" | jq -r .network_info.podman1.subnets[0].gateway)
+	create_container "$config_a1"
+	a1_pid=$CONTAINER_NS_PID
+	run_in_container_netns "$a1_pid" "dig" "+short" "A" "aone" "@$gw"
+	assert "$ip_a1"
+
+	# container has no v6 address, name exists so no NXDOMAIN
+	run_in_container_netns "$a1_pid" "dig" "AAAA" "aone" "@$gw"
+	assert "$output" =~ "status: NOERROR"
+	assert "$output" =~ "ANSWER: 0"
+
+	run_in_container_netns "$a1_pid" "dig" "+short" "ANY" "aone" "@$gw"
+	assert "$ip_a1"
+}
+
+@test "basic container - search domain answers are authoritative" {
+	subnet_a=$(random_subnet 5)
+	create_config "podman1" $(random_string 64) "aone" "$subnet_a" "a1" "1a"
+	config_a1=$config
+	ip_a1=$(echo "$config_a1" | jq -r .networks.podman1.static_ips[0])
+	gw=$(echo "$config_a1" | jq -r .network_info.podman1.subnets[0].gateway)
+	create_container "$config_a1"
+	a1_pid=$CONTAINER_NS_PID
+	run_in_container_netns "$a1_pid" "dig" "aone.dns.podman" "@$gw"
+	assert "$output" =~ "flags: qr aa"
+	assert "$output" =~ "$ip_a1"
+
+	# negative answers must carry the zone SOA
+	run_in_container_netns "$a1_pid" "dig" "bone.dns.podman" "@$gw"
+	assert "$output" =~ "status: NXDOMAIN"
+	assert "$output" =~ "AUTHORITY: 1"
+	assert "$output" =~ "dns.podman.[[:space:]]+10[[:space:]]+IN[[:space:]]+SOA"
+
+	run_in_container_netns "$a1_pid" "dig" "+short" "SOA" "dns.podman" "@$gw"
+	assert "$output" =~ "dns.podman. hostmaster.dns.podman."
+}
+
 @test "basic container - dns itself on container with ipaddress v6" {
 	setup_slirp4netns
 